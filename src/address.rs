@@ -0,0 +1,198 @@
+use std::fmt;
+
+use crate::{postcode::Postcode, Entry};
+
+/// Abbreviations of street types, expanded when they are the last word of the street name,
+/// so that e.g. "HIGH ST" and "HIGH STREET" are the same street but "ST JOHNS ROAD" is kept.
+const STREET_TYPES: [(&str, &str); 20] = [
+    ("AVE", "AVENUE"),
+    ("AV", "AVENUE"),
+    ("CL", "CLOSE"),
+    ("CRES", "CRESCENT"),
+    ("CT", "COURT"),
+    ("DR", "DRIVE"),
+    ("GDNS", "GARDENS"),
+    ("GRN", "GREEN"),
+    ("GRO", "GROVE"),
+    ("GR", "GROVE"),
+    ("HL", "HILL"),
+    ("LN", "LANE"),
+    ("PDE", "PARADE"),
+    ("PK", "PARK"),
+    ("PL", "PLACE"),
+    ("RD", "ROAD"),
+    ("SQ", "SQUARE"),
+    ("ST", "STREET"),
+    ("TER", "TERRACE"),
+    ("TERR", "TERRACE"),
+];
+
+/// The address of a sale, normalised to upper case with single spaces between the words
+/// and without full stops or commas, e.g. "FLAT 3, 12 HIGH STREET, LONDON, SE16 4AB".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub saon: String, // secondary addressable object name, e.g. flat number
+    pub paon: String, // primary addressable object name, e.g. house number or name
+    pub street: String,
+    pub locality: String,
+    pub city: String,
+    pub postcode: Option<Postcode>,
+}
+
+impl Address {
+    pub fn from_entry(entry: &Entry) -> Address {
+        Address {
+            saon: normalize(&entry.saon),
+            paon: normalize(&entry.paon),
+            street: normalize_street(&entry.street),
+            locality: normalize(&entry.locality),
+            city: normalize(&entry.city),
+            postcode: entry.postcode.clone(),
+        }
+    }
+
+    /// Identifies the property across its sales. None for addresses without a postcode
+    /// or any name or number, which can't be told apart from other properties.
+    pub fn key(&self) -> Option<PropertyKey> {
+        if self.paon.is_empty() && self.saon.is_empty() {
+            None
+        } else {
+            Some(PropertyKey {
+                postcode: self.postcode.clone()?,
+                street: self.street.clone(),
+                paon: self.paon.clone(),
+                saon: self.saon.clone(),
+            })
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The locality is often the same as the town, which is only written once.
+        let locality = if self.locality == self.city {
+            ""
+        } else {
+            &self.locality
+        };
+        let postcode = self
+            .postcode
+            .as_ref()
+            .map_or("", |postcode| postcode.unit());
+        let mut parts = building_parts(&self.saon, &self.paon, &self.street);
+        parts.extend([locality, &self.city, postcode].map(str::to_string));
+        write_parts(f, &parts)
+    }
+}
+
+/// Identifies a property across its sales. The Price Paid Dataset has no property identifier,
+/// so the normalised address within the postcode is used, leaving out the locality and town,
+/// which are recorded inconsistently between sales.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PropertyKey {
+    pub postcode: Postcode,
+    pub street: String,
+    pub paon: String,
+    pub saon: String,
+}
+
+impl fmt::Display for PropertyKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = building_parts(&self.saon, &self.paon, &self.street);
+        parts.push(self.postcode.unit().to_string());
+        write_parts(f, &parts)
+    }
+}
+
+/// The flat, then the building and street, with a house number on the same line as the street,
+/// e.g. ["FLAT 3", "12 HIGH STREET"] but ["ROSE COTTAGE", "HIGH STREET"].
+fn building_parts(saon: &str, paon: &str, street: &str) -> Vec<String> {
+    let numbered = paon.starts_with(|c: char| c.is_ascii_digit());
+    if numbered && !street.is_empty() {
+        vec![saon.to_string(), format!("{} {}", paon, street)]
+    } else {
+        vec![saon.to_string(), paon.to_string(), street.to_string()]
+    }
+}
+
+fn write_parts(f: &mut fmt::Formatter, parts: &[String]) -> fmt::Result {
+    let parts: Vec<&str> = parts
+        .iter()
+        .map(String::as_str)
+        .filter(|part| !part.is_empty())
+        .collect();
+    write!(f, "{}", parts.join(", "))
+}
+
+fn normalize(field: &str) -> String {
+    field
+        .split(|c: char| c.is_whitespace() || c == '.' || c == ',')
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_uppercase())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn normalize_street(street: &str) -> String {
+    let mut street = normalize(street);
+    let last_word = street.rfind(' ').map_or(0, |space| space + 1);
+    if last_word > 0 {
+        if let Some((_, expanded)) = STREET_TYPES
+            .iter()
+            .find(|(abbreviation, _)| street[last_word..] == **abbreviation)
+        {
+            street.replace_range(last_word.., expanded);
+        }
+    }
+    street
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(saon: &str, paon: &str, street: &str, locality: &str) -> Address {
+        let line = format!(
+            "{{1}},250000,2021-06-01 00:00,SE16 4AB,F,N,L,{},{},{},{},LONDON,SOUTHWARK,GREATER LONDON,A,A",
+            paon, saon, street, locality
+        );
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(line.as_bytes());
+        Address::from_entry(
+            &Entry::from_record(&reader.records().next().unwrap().unwrap()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn expands_street_types_at_the_end_only() {
+        assert_eq!(address("", "12", "High St.", "").street, "HIGH STREET");
+        assert_eq!(address("", "12", "st johns rd", "").street, "ST JOHNS ROAD");
+        assert_eq!(address("", "12", "ST", "").street, "ST");
+        assert_eq!(address("", "12", "THE GREEN", "").street, "THE GREEN");
+    }
+
+    #[test]
+    fn writes_the_flat_before_the_building() {
+        assert_eq!(
+            address("Flat 3", "12", "HIGH ST", "BERMONDSEY").to_string(),
+            "FLAT 3, 12 HIGH STREET, BERMONDSEY, LONDON, SE16 4AB"
+        );
+        assert_eq!(
+            address("FLAT 3", "ROSE COURT", "HIGH ST", "LONDON").to_string(),
+            "FLAT 3, ROSE COURT, HIGH STREET, LONDON, SE16 4AB"
+        );
+    }
+
+    #[test]
+    fn keys_match_however_the_address_is_written() {
+        let key = address("Flat 3", "12", "HIGH ST", "").key();
+        assert!(key.is_some());
+        assert_eq!(
+            key,
+            address("FLAT  3", "12", "High Street", "BERMONDSEY").key()
+        );
+        assert_ne!(key, address("FLAT 4", "12", "HIGH STREET", "").key());
+        assert_eq!(address("", "", "HIGH STREET", "").key(), None);
+    }
+}
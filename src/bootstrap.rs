@@ -0,0 +1,121 @@
+use crate::{error::Error, fnv};
+
+pub const DEFAULT_CONFIDENCE: f64 = 95.0;
+pub const DEFAULT_SEED: u64 = 1;
+
+/// Percentile bootstrap of the median and quantiles of a bucket: the prices are resampled
+/// with replacement, and the interval is the middle of the statistic over the resamples.
+#[derive(Debug, Clone)]
+pub struct Bootstrap {
+    pub resamples: usize,
+    pub confidence: f64, // percentage, e.g. 95 for 95% intervals
+    pub seed: u64,
+}
+
+/// The median and the quantiles of every resample.
+#[derive(Debug, Default)]
+pub struct Resamples {
+    pub medians: Vec<f32>,
+    pub quantiles: Vec<Vec<f32>>, // for each of the percentiles
+}
+
+impl Bootstrap {
+    pub fn new(resamples: usize, confidence: f64, seed: u64) -> Result<Bootstrap, Error> {
+        if confidence.is_nan() || confidence <= 0.0 || confidence >= 100.0 {
+            return Err(Error::Config(format!(
+                "confidence {} is not between 0 and 100",
+                confidence
+            )));
+        }
+        Ok(Bootstrap {
+            resamples,
+            confidence,
+            seed,
+        })
+    }
+
+    /// Resamples the sorted prices. The random numbers are seeded from the prices as well as
+    /// the seed, so that the intervals of a bucket don't depend on the other buckets.
+    pub fn resample(&self, prices: &[i32], percentiles: &[f64]) -> Resamples {
+        let mut resamples = Resamples {
+            medians: Vec::with_capacity(self.resamples),
+            quantiles: vec![Vec::with_capacity(self.resamples); percentiles.len()],
+        };
+        if prices.is_empty() {
+            return resamples;
+        }
+        let mut rng = SplitMix64::new(prices_hash(self.seed, prices));
+        // How often each of the prices is drawn is all that matters, as they are already sorted.
+        let mut counts = vec![0u32; prices.len()];
+        let mut cumulative = vec![0u32; prices.len()];
+        for _ in 0..self.resamples {
+            counts.fill(0);
+            for _ in 0..prices.len() {
+                counts[rng.below(prices.len())] += 1;
+            }
+            let mut total = 0;
+            for (count, cumulative) in counts.iter().zip(&mut cumulative) {
+                total += count;
+                *cumulative = total;
+            }
+            let quantile = |percentile: f64| {
+                let rank = percentile / 100.0 * (prices.len() - 1) as f64;
+                let price = |rank: usize| {
+                    prices[cumulative.partition_point(|&count| count as usize <= rank)] as f64
+                };
+                let (lower, upper) = (price(rank.floor() as usize), price(rank.ceil() as usize));
+                (lower + (upper - lower) * rank.fract()) as f32
+            };
+            resamples.medians.push(quantile(50.0));
+            for (values, &percentile) in resamples.quantiles.iter_mut().zip(percentiles) {
+                values.push(quantile(percentile));
+            }
+        }
+        resamples
+    }
+
+    /// The middle of the values, covering the confidence percentage of them.
+    pub fn interval(&self, values: &mut [f32]) -> Option<[f32; 2]> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_unstable_by(f32::total_cmp);
+        let tail = (100.0 - self.confidence) / 2.0;
+        let value = |percentile: f64| {
+            let rank = percentile / 100.0 * (values.len() - 1) as f64;
+            let (lower, upper) = (values[rank.floor() as usize], values[rank.ceil() as usize]);
+            lower + (upper - lower) * rank.fract() as f32
+        };
+        Some([value(tail), value(100.0 - tail)])
+    }
+}
+
+/// FNV-1a hash of the prices, starting from the seed.
+fn prices_hash(seed: u64, prices: &[i32]) -> u64 {
+    let bytes = prices.iter().flat_map(|price| price.to_le_bytes());
+    fnv::hash(fnv::OFFSET_BASIS ^ seed, bytes)
+}
+
+/// A small, fast random number generator, good enough for resampling.
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> SplitMix64 {
+        SplitMix64 { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed below the bound, with a bias too small to matter here.
+    fn below(&mut self, bound: usize) -> usize {
+        ((self.next() as u128 * bound as u128) >> 64) as usize
+    }
+}
@@ -0,0 +1,473 @@
+use chrono::{Datelike, NaiveDate};
+use memmap2::Mmap;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    error::Error, fnv, DurationOfTransfer, Entry, PpdCategory, PropertyAge, PropertyType,
+    RecordStatus,
+};
+
+// Cache file layout (all numbers are little endian):
+//
+// - magic bytes and format version
+// - size, modification time and sampled hash of the source file
+// - number of rows, number of distinct strings and number of records left out of the cache
+//   as they failed to parse (only with --lenient)
+// - string table: offsets of every string (one more than the number of strings) followed by the string data
+// - numeric columns: price (i32), date (i32, days since 1 January of year 1)
+// - string columns (u32 indices into the string table): id, postcode, PAON, SAON, street, locality,
+//   town/city, district and county
+// - enum columns (u8 codes): property type, old/new, duration, PPD category type and record status
+
+const MAGIC: &[u8; 8] = b"HOMEUKPP";
+const VERSION: u32 = 2;
+const EXTENSION: &str = "cache";
+const STRING_COLUMNS: usize = 9;
+const ENUM_COLUMNS: usize = 5;
+const HASH_SAMPLE_LEN: u64 = 1 << 20;
+const CORRUPTED: &str = "the cache is corrupted, try running ingest again";
+
+// Enums are encoded as their index in these arrays.
+const PROPERTY_TYPES: [PropertyType; 5] = [
+    PropertyType::Detached,
+    PropertyType::SemiDetached,
+    PropertyType::Terraced,
+    PropertyType::Flat,
+    PropertyType::Other,
+];
+const PROPERTY_AGES: [PropertyAge; 2] = [PropertyAge::New, PropertyAge::Old];
+const DURATIONS: [DurationOfTransfer; 2] =
+    [DurationOfTransfer::Freehold, DurationOfTransfer::Leasehold];
+const PPD_CATEGORIES: [PpdCategory; 2] = [PpdCategory::Standard, PpdCategory::Additional];
+const RECORD_STATUSES: [RecordStatus; 3] = [
+    RecordStatus::Added,
+    RecordStatus::Changed,
+    RecordStatus::Deleted,
+];
+
+pub fn path_for(source: &str) -> String {
+    format!("{}.{}", source, EXTENSION)
+}
+
+/// Identifies the version of the source file the cache was created from.
+/// Hashing the whole file would take about as long as parsing it, so only
+/// its first and last megabyte are hashed.
+#[derive(Debug, PartialEq, Eq)]
+struct SourceStamp {
+    len: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+    hash: u64,
+}
+
+impl SourceStamp {
+    fn of(path: &str) -> Result<SourceStamp, Error> {
+        let mut file = File::open(path).map_err(Error::io(path))?;
+        let metadata = file.metadata().map_err(Error::io(path))?;
+        let modified = metadata
+            .modified()
+            .map_err(Error::io(path))?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut hash = fnv::OFFSET_BASIS;
+        let mut sample = Vec::new();
+        let len = metadata.len();
+        for start in [0, len.saturating_sub(HASH_SAMPLE_LEN)] {
+            sample.clear();
+            file.seek(SeekFrom::Start(start)).map_err(Error::io(path))?;
+            (&mut file)
+                .take(HASH_SAMPLE_LEN)
+                .read_to_end(&mut sample)
+                .map_err(Error::io(path))?;
+            hash = fnv::hash(hash, sample.iter().copied());
+        }
+
+        Ok(SourceStamp {
+            len,
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+            hash,
+        })
+    }
+}
+
+/// Collects the entries column by column, interning the strings.
+#[derive(Debug, Default)]
+pub struct CacheBuilder {
+    strings: Vec<String>,
+    string_indices: HashMap<String, u32>,
+    prices: Vec<i32>,
+    dates: Vec<i32>,
+    string_columns: [Vec<u32>; STRING_COLUMNS],
+    enum_columns: [Vec<u8>; ENUM_COLUMNS],
+}
+
+impl CacheBuilder {
+    pub fn add(&mut self, entry: &Entry) {
+        self.prices.push(entry.price);
+        self.dates.push(entry.date.num_days_from_ce());
+
+        let postcode = entry
+            .postcode
+            .as_ref()
+            .map_or("", |postcode| postcode.unit());
+        let strings = [
+            entry.id.as_str(),
+            postcode,
+            &entry.paon,
+            &entry.saon,
+            &entry.street,
+            &entry.locality,
+            &entry.city,
+            &entry.district,
+            &entry.county,
+        ];
+        for (column, string) in strings.into_iter().enumerate() {
+            let index = self.intern(string);
+            self.string_columns[column].push(index);
+        }
+
+        let codes = [
+            encode(&PROPERTY_TYPES, entry.property_type),
+            encode(&PROPERTY_AGES, entry.property_age),
+            encode(&DURATIONS, entry.duration),
+            encode(&PPD_CATEGORIES, entry.ppd_category),
+            encode(&RECORD_STATUSES, entry.record_status),
+        ];
+        for (column, code) in codes.into_iter().enumerate() {
+            self.enum_columns[column].push(code);
+        }
+    }
+
+    fn intern(&mut self, string: &str) -> u32 {
+        if let Some(index) = self.string_indices.get(string) {
+            return *index;
+        }
+        let index = self.strings.len() as u32;
+        self.strings.push(string.to_string());
+        self.string_indices.insert(string.to_string(), index);
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.prices.len()
+    }
+
+    /// Writes the cache for the given source file, with the number of its records
+    /// that failed to parse and were left out.
+    pub fn write(&self, source: &str, quarantined: usize) -> Result<(), Error> {
+        let stamp = SourceStamp::of(source)?;
+        let path = path_for(source);
+        let mut writer = BufWriter::new(File::create(&path).map_err(Error::io(&path))?);
+
+        let mut write = |bytes: &[u8]| writer.write_all(bytes).map_err(Error::io(&path));
+        write(MAGIC)?;
+        write(&VERSION.to_le_bytes())?;
+        write(&stamp.len.to_le_bytes())?;
+        write(&stamp.modified_secs.to_le_bytes())?;
+        write(&stamp.modified_nanos.to_le_bytes())?;
+        write(&stamp.hash.to_le_bytes())?;
+        write(&(self.len() as u64).to_le_bytes())?;
+        write(&(self.strings.len() as u64).to_le_bytes())?;
+        write(&(quarantined as u64).to_le_bytes())?;
+
+        let mut offset: u64 = 0;
+        write(&offset.to_le_bytes())?;
+        for string in &self.strings {
+            offset += string.len() as u64;
+            write(&offset.to_le_bytes())?;
+        }
+        for string in &self.strings {
+            write(string.as_bytes())?;
+        }
+
+        for price in &self.prices {
+            write(&price.to_le_bytes())?;
+        }
+        for date in &self.dates {
+            write(&date.to_le_bytes())?;
+        }
+        for column in &self.string_columns {
+            for index in column {
+                write(&index.to_le_bytes())?;
+            }
+        }
+        for column in &self.enum_columns {
+            write(column)?;
+        }
+
+        writer.flush().map_err(Error::io(&path))
+    }
+}
+
+fn encode<T: PartialEq>(values: &[T], value: T) -> u8 {
+    values.iter().position(|v| *v == value).unwrap() as u8
+}
+
+/// A memory-mapped cache, read column by column without copying.
+pub struct Cache {
+    path: String,
+    mmap: Mmap,
+    rows: usize,
+    strings: usize,
+    quarantined: usize,
+    string_offsets: usize,
+    string_data: usize,
+    prices: usize,
+    dates: usize,
+    string_columns: usize,
+    enum_columns: usize,
+}
+
+impl Cache {
+    /// Opens the cache of the given source file.
+    /// Returns `None` if there is no cache or it was created from a different version of the file,
+    /// or if records were left out of it by a lenient ingest and the run isn't lenient.
+    pub fn open(source: &str, lenient: bool) -> Result<Option<Cache>, Error> {
+        let path = path_for(source);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        // Safety: the cache is only ever written by the ingest step and not modified while mapped.
+        let mmap = unsafe { Mmap::map(&file) }.map_err(Error::io(&path))?;
+
+        let mut reader = HeaderReader {
+            bytes: &mmap,
+            offset: 0,
+        };
+        if reader.bytes(MAGIC.len()) != Some(MAGIC.as_slice()) || reader.u32() != Some(VERSION) {
+            println!("Ignoring {} as it was written by another version", path);
+            return Ok(None);
+        }
+        let stamp = SourceStamp {
+            len: reader.u64().unwrap_or_default(),
+            modified_secs: reader.u64().unwrap_or_default(),
+            modified_nanos: reader.u32().unwrap_or_default(),
+            hash: reader.u64().unwrap_or_default(),
+        };
+        if stamp != SourceStamp::of(source)? {
+            println!(
+                "Ignoring {} as {} has changed since, run ingest again to update it",
+                path, source
+            );
+            return Ok(None);
+        }
+        let rows = reader.u64().unwrap_or_default() as usize;
+        let strings = reader.u64().unwrap_or_default() as usize;
+        let quarantined = reader.u64().unwrap_or_default() as usize;
+        if quarantined > 0 && !lenient {
+            println!(
+                "Ignoring {} as {} records of {} failed to parse when it was created with --lenient",
+                path, quarantined, source
+            );
+            return Ok(None);
+        }
+
+        let corrupted = |path| Error::Input {
+            path,
+            message: CORRUPTED.to_string(),
+        };
+        let string_offsets = reader.offset;
+        let string_data = strings
+            .checked_add(1)
+            .and_then(|count| count.checked_mul(8))
+            .and_then(|len| len.checked_add(string_offsets))
+            .filter(|&string_data| string_data <= mmap.len());
+        let Some(string_data) = string_data else {
+            return Err(corrupted(path));
+        };
+        let mut cache = Cache {
+            path,
+            mmap,
+            rows,
+            strings,
+            quarantined,
+            string_offsets,
+            string_data,
+            prices: 0,
+            dates: 0,
+            string_columns: 0,
+            enum_columns: 0,
+        };
+        // Each row takes 4 bytes for each numeric and string column and 1 for each enum column.
+        let row_len = 4 + 4 + STRING_COLUMNS * 4 + ENUM_COLUMNS;
+        let string_data_len = cache.read_u64(string_offsets + strings * 8) as usize;
+        let len = rows
+            .checked_mul(row_len)
+            .and_then(|len| len.checked_add(string_data))
+            .and_then(|len| len.checked_add(string_data_len));
+        if len != Some(cache.mmap.len()) {
+            return Err(corrupted(cache.path));
+        }
+        cache.prices = string_data + string_data_len;
+        cache.dates = cache.prices + rows * 4;
+        cache.string_columns = cache.dates + rows * 4;
+        cache.enum_columns = cache.string_columns + STRING_COLUMNS * rows * 4;
+
+        Ok(Some(cache))
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    /// Number of records of the source file that failed to parse and were left out.
+    pub fn quarantined(&self) -> usize {
+        self.quarantined
+    }
+
+    /// Splits the rows into at most `count` ranges of roughly equal size.
+    pub fn split(&self, count: usize) -> Vec<Range<usize>> {
+        let chunk_len = self.rows.div_ceil(count.max(1)).max(1);
+        (0..self.rows)
+            .step_by(chunk_len)
+            .map(|start| start..(start + chunk_len).min(self.rows))
+            .collect()
+    }
+
+    /// The entry of the given row, which has to be below the number of rows. The sizes of the
+    /// columns are checked when opening, their values here, as a corrupted cache may still
+    /// have the right size.
+    pub fn entry(&self, row: usize) -> Result<Entry, Error> {
+        let string = |column: usize| {
+            let index = self.read_u32(self.string_columns + (column * self.rows + row) * 4);
+            self.string(index as usize)
+        };
+        let code = |column: usize| self.mmap[self.enum_columns + column * self.rows + row] as usize;
+        let days = self.read_u32(self.dates + row * 4) as i32;
+
+        Ok(Entry {
+            id: string(0)?,
+            price: self.read_u32(self.prices + row * 4) as i32,
+            date: NaiveDate::from_num_days_from_ce_opt(days).ok_or_else(|| self.corrupted())?,
+            postcode: string(1)?.parse().ok(), // only valid postcodes are cached
+            property_type: self.decode(&PROPERTY_TYPES, code(0))?,
+            property_age: self.decode(&PROPERTY_AGES, code(1))?,
+            duration: self.decode(&DURATIONS, code(2))?,
+            paon: string(2)?,
+            saon: string(3)?,
+            street: string(4)?,
+            locality: string(5)?,
+            city: string(6)?,
+            district: string(7)?,
+            county: string(8)?,
+            ppd_category: self.decode(&PPD_CATEGORIES, code(3))?,
+            record_status: self.decode(&RECORD_STATUSES, code(4))?,
+        })
+    }
+
+    fn string(&self, index: usize) -> Result<String, Error> {
+        if index >= self.strings {
+            return Err(self.corrupted());
+        }
+        let start = self.read_u64(self.string_offsets + index * 8) as usize;
+        let end = self.read_u64(self.string_offsets + (index + 1) * 8) as usize;
+        let bytes = self.mmap[self.string_data..self.prices]
+            .get(start..end)
+            .ok_or_else(|| self.corrupted())?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn decode<T: Copy>(&self, values: &[T], code: usize) -> Result<T, Error> {
+        values.get(code).copied().ok_or_else(|| self.corrupted())
+    }
+
+    fn corrupted(&self) -> Error {
+        Error::Input {
+            path: self.path.clone(),
+            message: CORRUPTED.to_string(),
+        }
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.mmap[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.mmap[offset..offset + 8].try_into().unwrap())
+    }
+}
+
+struct HeaderReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> HeaderReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.offset..self.offset + len)?;
+        self.offset += len;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORDS: [&str; 2] = [
+        r#""{00000000}","572000","2017-10-28 00:00","SE16 7CD","D","N","L","121","FLAT 21","JAMAICA RD","","LONDON","SOUTHWARK","GREATER LONDON","A","A""#,
+        r#""{00000001}","936000","2022-01-27 00:00","","O","Y","F","69","","BOROUGH RD","","LONDON","SOUTHWARK","GREATER LONDON","B","D""#,
+    ];
+
+    fn entries() -> Vec<Entry> {
+        RECORDS
+            .iter()
+            .map(|line| {
+                let mut reader = csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .from_reader(line.as_bytes());
+                Entry::from_record(&reader.records().next().unwrap().unwrap()).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = std::env::temp_dir().join(format!("home-uk-cache-{}.csv", std::process::id()));
+        let source = source.to_str().unwrap();
+        std::fs::write(source, RECORDS.join("\n")).unwrap();
+
+        let mut builder = CacheBuilder::default();
+        for entry in entries() {
+            builder.add(&entry);
+        }
+        builder.write(source, 1).unwrap();
+        assert!(Cache::open(source, false).unwrap().is_none());
+        let cache = Cache::open(source, true).unwrap().unwrap();
+        assert_eq!((cache.len(), cache.quarantined()), (2, 1));
+        for (row, entry) in entries().iter().enumerate() {
+            let cached = cache.entry(row).unwrap();
+            assert_eq!(format!("{:?}", cached), format!("{:?}", entry));
+        }
+        drop(cache);
+
+        // An unknown enum code is an error rather than a panic.
+        let mut bytes = std::fs::read(path_for(source)).unwrap();
+        *bytes.last_mut().unwrap() = u8::MAX;
+        std::fs::write(path_for(source), bytes).unwrap();
+        let cache = Cache::open(source, true).unwrap().unwrap();
+        assert!(cache.entry(0).is_ok());
+        assert!(cache.entry(1).is_err());
+        drop(cache);
+
+        std::fs::remove_file(path_for(source)).unwrap();
+        std::fs::remove_file(source).unwrap();
+    }
+}
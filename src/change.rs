@@ -0,0 +1,214 @@
+use chrono::{Months, NaiveDate};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{
+    bootstrap::Bootstrap,
+    period::{Period, DAYS_PER_YEAR},
+    stats::PriceBucket,
+    Buckets, PostcodeNode, PropertyAge, PropertyType, RegionBuckets,
+};
+
+pub const DEFAULT_MIN_CHANGE_SALES: usize = 10;
+
+/// Change of the median price of a bucket against an earlier period of the same series.
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    from: NaiveDate, // start of the earlier period
+    absolute: f32,
+    percent: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<[f32; 2]>, // bootstrap confidence interval of the percentage
+    low_sample: bool, // whether either period has too few sales for the change to be reliable
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Changes {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous: Option<Change>, // against the previous period
+    #[serde(skip_serializing_if = "Option::is_none")]
+    year_ago: Option<Change>, // against the period a year earlier
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since_first: Option<Growth>, // over every period up to this one
+}
+
+/// Compound annual growth rate of the median price since the first period of the series,
+/// which at the last period is the growth over the whole range.
+#[derive(Debug, Clone, Serialize)]
+pub struct Growth {
+    from: NaiveDate,
+    cagr: f64, // e.g. 0.05 for 5% a year
+    low_sample: bool,
+}
+
+/// A series is the buckets of a postcode or region, property type and age over time,
+/// in nominal or real prices.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SeriesKey {
+    region: bool, // region names and postcodes are kept apart, as they could be the same
+    code: String,
+    property_type: PropertyType,
+    property_age: PropertyAge,
+    real: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Point {
+    start: NaiveDate,
+    count: usize,
+    median: f32,
+    bootstrap_medians: Vec<f32>, // only kept while later periods can still be compared to it
+}
+
+/// Remembers the median price of every series in the periods written so far,
+/// to add the changes against the earlier periods to the buckets of the next ones.
+#[derive(Debug)]
+pub struct ChangeTracker {
+    period: Period,
+    origin: NaiveDate,
+    min_sales: usize,
+    bootstrap: Option<Bootstrap>,
+    series: BTreeMap<SeriesKey, BTreeMap<NaiveDate, Point>>,
+}
+
+impl ChangeTracker {
+    pub fn new(
+        period: Period,
+        origin: NaiveDate,
+        min_sales: usize,
+        bootstrap: Option<Bootstrap>,
+    ) -> ChangeTracker {
+        ChangeTracker {
+            period,
+            origin,
+            min_sales,
+            bootstrap,
+            series: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the changes to the buckets of the period (or of the window of periods)
+    /// starting on the given date. Periods have to be added in order.
+    pub fn add_changes(
+        &mut self,
+        start: NaiveDate,
+        node: &mut PostcodeNode<PriceBucket>,
+        regions: &mut RegionBuckets<PriceBucket>,
+    ) {
+        self.add_node_changes(start, node);
+        for (name, buckets) in regions {
+            self.add_bucket_changes(start, true, name, buckets);
+        }
+    }
+
+    fn add_node_changes(&mut self, start: NaiveDate, node: &mut PostcodeNode<PriceBucket>) {
+        for (code, child) in &mut node.children {
+            self.add_bucket_changes(start, false, code, &mut child.buckets);
+            self.add_node_changes(start, child);
+        }
+    }
+
+    fn add_bucket_changes(
+        &mut self,
+        start: NaiveDate,
+        region: bool,
+        code: &str,
+        buckets: &mut Buckets<PriceBucket>,
+    ) {
+        for (property_type, age_buckets) in buckets {
+            for (property_age, bucket) in age_buckets {
+                let mut key = SeriesKey {
+                    region,
+                    code: code.to_string(),
+                    property_type: *property_type,
+                    property_age: *property_age,
+                    real: false,
+                };
+                self.add_change(start, key.clone(), bucket);
+                if let Some(real) = &mut bucket.real {
+                    key.real = true;
+                    self.add_change(start, key, real);
+                }
+            }
+        }
+    }
+
+    fn add_change(&mut self, start: NaiveDate, key: SeriesKey, bucket: &mut PriceBucket) {
+        let point = Point {
+            start,
+            count: bucket.count,
+            median: bucket.median,
+            bootstrap_medians: std::mem::take(&mut bucket.bootstrap_medians),
+        };
+        let previous_start = start
+            .pred_opt()
+            .map(|date| self.period.start(date, self.origin));
+        let year_ago_start = start
+            .checked_sub_months(Months::new(12))
+            .map(|date| self.period.start(date, self.origin));
+        let min_sales = self.min_sales;
+        let bootstrap = self.bootstrap.as_ref();
+        let series = self.series.entry(key).or_default();
+        let earlier = |date: Option<NaiveDate>| date.and_then(|date| series.get(&date));
+        let change = |from| change(from, &point, min_sales, bootstrap);
+        let changes = Changes {
+            previous: earlier(previous_start).map(change),
+            year_ago: earlier(year_ago_start).map(change),
+            since_first: series
+                .values()
+                .next()
+                .and_then(|first| growth(first, &point, min_sales)),
+        };
+        if changes.previous.is_some() || changes.year_ago.is_some() || changes.since_first.is_some()
+        {
+            bucket.change = Some(changes);
+        }
+        // Later periods are only compared to the ones from a year before them onwards.
+        if let Some(year_ago_start) = year_ago_start {
+            for point in series.range_mut(..year_ago_start).rev() {
+                if point.1.bootstrap_medians.is_empty() {
+                    break;
+                }
+                point.1.bootstrap_medians = Vec::new();
+            }
+        }
+        series.insert(start, point);
+    }
+}
+
+fn change(from: &Point, to: &Point, min_sales: usize, bootstrap: Option<&Bootstrap>) -> Change {
+    let percent = |from: f32, to: f32| {
+        if from > 0.0 {
+            (to - from) / from * 100.0
+        } else {
+            0.0
+        }
+    };
+    // The resamples of the periods are independent, so any pairing of them will do.
+    let mut percents: Vec<f32> = from
+        .bootstrap_medians
+        .iter()
+        .zip(&to.bootstrap_medians)
+        .map(|(&from, &to)| percent(from, to))
+        .collect();
+    Change {
+        from: from.start,
+        absolute: to.median - from.median,
+        percent: percent(from.median, to.median),
+        interval: bootstrap.and_then(|bootstrap| bootstrap.interval(&mut percents)),
+        low_sample: from.count < min_sales || to.count < min_sales,
+    }
+}
+
+/// None if there is no growth rate to speak of, e.g. for a zero price.
+fn growth(from: &Point, to: &Point, min_sales: usize) -> Option<Growth> {
+    let years = (to.start - from.start).num_days() as f64 / DAYS_PER_YEAR;
+    if years <= 0.0 || from.median <= 0.0 || to.median <= 0.0 {
+        return None;
+    }
+    Some(Growth {
+        from: from.start,
+        cagr: (to.median as f64 / from.median as f64).powf(1.0 / years) - 1.0,
+        low_sample: from.count < min_sales || to.count < min_sales,
+    })
+}
@@ -0,0 +1,114 @@
+use chrono::{Local, Months, NaiveDate};
+use std::collections::BTreeMap;
+
+use crate::error::Error;
+
+/// Month formats of the price index file: "2024-01" as well as "2024 JAN",
+/// which the ONS uses for the monthly rows of its CPI and CPIH downloads.
+/// Each is completed to a date by appending the first day of the month.
+const MONTH_FORMATS: [(&str, &str); 2] = [("-01", "%Y-%m-%d"), (" 01", "%Y %b %d")];
+
+/// Converts prices to the money of a reference month, using a monthly consumer price index
+/// such as CPI or CPIH.
+#[derive(Debug, Clone)]
+pub struct Deflator {
+    index: BTreeMap<NaiveDate, f64>, // by the first day of the month
+    reference: f64,                  // index value of the reference month
+}
+
+impl Deflator {
+    /// Reads the index from a CSV file with the month in the first column and the index value
+    /// in the second one. Other rows, such as headers, metadata or yearly and quarterly values,
+    /// are skipped. The reference month defaults to the last month of the index.
+    pub fn load(path: &str, reference: Option<NaiveDate>) -> Result<Deflator, Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_path(path)
+            .map_err(Error::csv(path))?;
+        let mut index = BTreeMap::new();
+        for record in reader.records() {
+            let record = record.map_err(Error::csv(path))?;
+            let (Some(month), Some(value)) = (record.get(0), record.get(1)) else {
+                continue;
+            };
+            let (Ok(month), Ok(value)) = (parse_month(month), value.trim().parse::<f64>()) else {
+                continue;
+            };
+            if value > 0.0 {
+                index.insert(month, value);
+            }
+        }
+
+        let input_error = |message: String| Error::Input {
+            path: path.to_string(),
+            message,
+        };
+        let reference = match reference {
+            Some(month) => *index.get(&month).ok_or_else(|| {
+                input_error(format!("no index value for {}", month.format("%Y-%m")))
+            })?,
+            None => *index
+                .values()
+                .next_back()
+                .ok_or_else(|| input_error("no monthly index values".to_string()))?,
+        };
+        Ok(Deflator { index, reference })
+    }
+
+    /// Checks that the index covers the sales from `from` to `to` (or to today).
+    /// Sales before its first month would silently be left out of the real stats only,
+    /// so that is an error, while sales after its last month are converted with its last value.
+    pub fn check_range(
+        &self,
+        path: &str,
+        from: NaiveDate,
+        to: Option<NaiveDate>,
+    ) -> Result<(), Error> {
+        let (Some(first), Some(last)) = (self.index.keys().next(), self.index.keys().next_back())
+        else {
+            return Ok(());
+        };
+        if from < *first {
+            return Err(Error::Input {
+                path: path.to_string(),
+                message: format!(
+                    "the index starts in {}, after the sales from {} (set --from to {} or later)",
+                    first.format("%Y-%m"),
+                    from,
+                    first
+                ),
+            });
+        }
+        let to = to.unwrap_or_else(|| Local::now().date_naive());
+        if last
+            .checked_add_months(Months::new(1))
+            .is_some_and(|next| next <= to)
+        {
+            println!(
+                "The index in {} ends in {}, the sales after it are converted with its last value",
+                path,
+                last.format("%Y-%m")
+            );
+        }
+        Ok(())
+    }
+
+    /// The price in the money of the reference month. Sales after the last month of the index
+    /// use its last value, sales before the first month can't be converted (see `check_range`).
+    pub fn real_price(&self, date: NaiveDate, price: i32) -> Option<i32> {
+        let (_, value) = self.index.range(..=date).next_back()?;
+        Some((price as f64 * self.reference / value).round() as i32)
+    }
+}
+
+/// Parses a month such as "2024-01" or "2024 JAN" to its first day.
+pub fn parse_month(value: &str) -> Result<NaiveDate, String> {
+    let value = value.trim();
+    MONTH_FORMATS
+        .iter()
+        .find_map(|(day, format)| {
+            NaiveDate::parse_from_str(&format!("{}{}", value, day), format).ok()
+        })
+        .ok_or_else(|| format!("invalid month {:?}, expected e.g. 2024-01", value))
+}
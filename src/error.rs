@@ -0,0 +1,123 @@
+use std::{fmt, io};
+
+use crate::COLUMN_NAMES;
+
+/// A problem with a single CSV record, before it is known which file and line it came from.
+#[derive(Debug, Clone)]
+pub struct RecordError {
+    pub column: Option<usize>, // none if the problem is with the record as a whole
+    pub value: String,
+    pub message: String,
+}
+
+impl RecordError {
+    pub fn column(column: usize, value: &str, message: impl fmt::Display) -> RecordError {
+        RecordError {
+            column: Some(column),
+            value: value.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.column {
+            Some(column) => write!(
+                f,
+                "column {} ({}): {} in {:?}",
+                column + 1,
+                COLUMN_NAMES[column],
+                self.message,
+                self.value
+            ),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: String,
+        source: io::Error,
+    },
+    Csv {
+        path: String,
+        source: csv::Error,
+    },
+    Json {
+        path: String,
+        source: serde_json::Error,
+    },
+    Input {
+        path: String,
+        message: String,
+    },
+    Record {
+        path: String,
+        line: u64,
+        error: RecordError,
+    },
+    Config(String), // invalid combination of options, unknown names, etc.
+}
+
+impl Error {
+    pub fn io(path: &str) -> impl FnOnce(io::Error) -> Error + '_ {
+        move |source| Error::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub fn csv(path: &str) -> impl FnOnce(csv::Error) -> Error + '_ {
+        move |source| Error::Csv {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub fn json(path: &str) -> impl FnOnce(serde_json::Error) -> Error + '_ {
+        move |source| Error::Json {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    /// Makes the line number of a record error absolute, when the record was read
+    /// from a part of the file that starts at the given line offset.
+    pub fn with_line_offset(self, offset: u64) -> Error {
+        match self {
+            Error::Record { path, line, error } => Error::Record {
+                path,
+                line: line + offset,
+                error,
+            },
+            error => error,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path, source),
+            Error::Csv { path, source } => write!(f, "{}: {}", path, source),
+            Error::Json { path, source } => write!(f, "{}: {}", path, source),
+            Error::Input { path, message } => write!(f, "{}: {}", path, message),
+            Error::Record { path, line, error } => write!(f, "{}, line {}: {}", path, line, error),
+            Error::Config(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Csv { source, .. } => Some(source),
+            Error::Json { source, .. } => Some(source),
+            Error::Input { .. } | Error::Record { .. } | Error::Config(_) => None,
+        }
+    }
+}
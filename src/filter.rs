@@ -0,0 +1,116 @@
+use chrono::NaiveDate;
+
+use crate::{
+    postcode::Postcode, DurationOfTransfer, Entry, PpdCategory, PropertyAge, PropertyType,
+};
+
+/// Decides which sales are included in the stats.
+/// Empty lists don't filter anything out.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>, // inclusive
+    pub durations: Vec<DurationOfTransfer>,
+    pub property_types: Vec<PropertyType>,
+    pub property_ages: Vec<PropertyAge>,
+    pub ppd_categories: Vec<PpdCategory>,
+    pub min_price: Option<i32>,
+    pub max_price: Option<i32>, // inclusive
+    pub postcodes: Vec<PostcodePattern>,
+}
+
+impl Filter {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        // The cheapest checks go first, as most of the records are filtered out.
+        self.from.is_none_or(|from| entry.date >= from)
+            && self.to.is_none_or(|to| entry.date <= to)
+            && self.min_price.is_none_or(|min| entry.price >= min)
+            && self.max_price.is_none_or(|max| entry.price <= max)
+            && includes(&self.durations, &entry.duration)
+            && includes(&self.property_types, &entry.property_type)
+            && includes(&self.property_ages, &entry.property_age)
+            && includes(&self.ppd_categories, &entry.ppd_category)
+            && (self.postcodes.is_empty()
+                || entry.postcode.as_ref().is_some_and(|postcode| {
+                    self.postcodes
+                        .iter()
+                        .any(|pattern| pattern.matches(postcode))
+                }))
+    }
+}
+
+fn includes<T: PartialEq>(values: &[T], value: &T) -> bool {
+    values.is_empty() || values.contains(value)
+}
+
+/// Either a postcode area, district, sector or unit (e.g. "SE", "SE16", "SE16 4" or "SE16 4AB"),
+/// which matches the postcodes within it, or a prefix ending with "*" (e.g. "SE1*"),
+/// which matches every postcode that starts with it (SE1, SE10 to SE19).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PostcodePattern {
+    Region(String),
+    Prefix(String),
+}
+
+impl PostcodePattern {
+    /// Full postcodes are normalised like the ones of the sales, e.g. "se164ab" to "SE16 4AB".
+    pub fn parse(str: &str) -> PostcodePattern {
+        if let Ok(postcode) = str.parse::<Postcode>() {
+            return PostcodePattern::Region(postcode.unit().to_string());
+        }
+        let normalized = str
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_ascii_uppercase();
+        match normalized.strip_suffix('*') {
+            Some(prefix) => PostcodePattern::Prefix(prefix.to_string()),
+            None => PostcodePattern::Region(normalized),
+        }
+    }
+
+    pub fn matches(&self, postcode: &Postcode) -> bool {
+        match self {
+            PostcodePattern::Region(region) => [
+                postcode.area(),
+                postcode.district(),
+                postcode.sector(),
+                postcode.unit(),
+            ]
+            .contains(&region.as_str()),
+            PostcodePattern::Prefix(prefix) => postcode.unit().starts_with(prefix.as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units_match_however_they_are_written() {
+        let postcode: Postcode = "SE16 4AB".parse().unwrap();
+        for pattern in [
+            "se164ab",
+            "SE16 4AB",
+            " se16  4ab ",
+            "se16 4",
+            "SE16",
+            "se",
+            "SE1*",
+        ] {
+            assert!(
+                PostcodePattern::parse(pattern).matches(&postcode),
+                "{}",
+                pattern
+            );
+        }
+        for pattern in ["SE164AC", "SE1", "SE16 5", "SE17*"] {
+            assert!(
+                !PostcodePattern::parse(pattern).matches(&postcode),
+                "{}",
+                pattern
+            );
+        }
+    }
+}
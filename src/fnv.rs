@@ -0,0 +1,11 @@
+/// Start of an FNV-1a hash.
+pub const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const PRIME: u64 = 0x100000001b3;
+
+/// Continues the FNV-1a hash with the bytes. Unlike the standard library hasher,
+/// it's stable across Rust versions, so the hashes can be stored or used as seeds.
+pub fn hash(hash: u64, bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes
+        .into_iter()
+        .fold(hash, |hash, byte| (hash ^ byte as u64).wrapping_mul(PRIME))
+}
@@ -0,0 +1,149 @@
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::{collections::BTreeMap, fs::File};
+
+use crate::{
+    error::Error,
+    period::DAYS_PER_YEAR,
+    postcode::Postcode,
+    sales::{Sale, SalesHistory},
+    stats::find_median,
+    PropertyType,
+};
+
+/// Holdings shorter than this have no annualised return, as compounding a few days
+/// or months of price change up to a year blows up to meaningless rates.
+const MIN_ANNUALISED_DAYS: i64 = 365;
+
+/// The time between two consecutive sales of a property.
+#[derive(Debug, Serialize)]
+pub struct Holding {
+    bought: NaiveDate,
+    sold: NaiveDate,
+    bought_for: i32,
+    sold_for: i32,
+    days: i64,
+    gain: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annualised_return: Option<f64>, // compound yearly return, e.g. 0.05 for 5% a year
+}
+
+impl Holding {
+    /// None for sales on the same day, which have no meaningful return.
+    fn new(bought: &Sale, sold: &Sale) -> Option<Holding> {
+        let days = (sold.date - bought.date).num_days();
+        if days <= 0 || bought.price <= 0 {
+            return None;
+        }
+        let ratio = sold.price as f64 / bought.price as f64;
+        Some(Holding {
+            bought: bought.date,
+            sold: sold.date,
+            bought_for: bought.price,
+            sold_for: sold.price,
+            days,
+            gain: sold.price as i64 - bought.price as i64,
+            annualised_return: (days >= MIN_ANNUALISED_DAYS)
+                .then(|| ratio.powf(DAYS_PER_YEAR / days as f64) - 1.0),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PropertyHistory {
+    address: String,
+    postcode: Postcode,
+    property_type: PropertyType, // as of the last sale
+    sales: Vec<Sale>,
+    holdings: Vec<Holding>,
+}
+
+/// Returns of the properties in a postcode area, district or sector.
+#[derive(Debug, Serialize)]
+pub struct PostcodeSummary {
+    properties: usize,
+    holdings: usize,
+    median_holding_years: f64,
+    median_gain: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    median_annualised_return: Option<f64>, // of the holdings of a year or more
+    loss_share: f64, // share of the holdings sold for less than they were bought for
+}
+
+#[derive(Debug, Serialize)]
+pub struct History {
+    postcodes: BTreeMap<String, PostcodeSummary>,
+    properties: Vec<PropertyHistory>,
+}
+
+/// Follows every property sold more than once through its sales.
+pub fn history(sales: SalesHistory) -> History {
+    let properties: Vec<PropertyHistory> = sales
+        .into_sorted()
+        .into_iter()
+        .filter(|(_, sales)| sales.len() > 1)
+        .map(|(key, sales)| PropertyHistory {
+            address: key.to_string(),
+            property_type: sales[sales.len() - 1].property_type,
+            holdings: sales
+                .windows(2)
+                .filter_map(|pair| Holding::new(&pair[0], &pair[1]))
+                .collect(),
+            sales,
+            postcode: key.postcode,
+        })
+        .collect();
+
+    let mut holdings_by_postcode: BTreeMap<&str, (usize, Vec<&Holding>)> = BTreeMap::new();
+    for property in &properties {
+        let postcode = &property.postcode;
+        for code in [postcode.area(), postcode.district(), postcode.sector()] {
+            let (count, holdings) = holdings_by_postcode.entry(code).or_default();
+            *count += 1;
+            holdings.extend(&property.holdings);
+        }
+    }
+
+    let postcodes = holdings_by_postcode
+        .into_iter()
+        .map(|(code, (count, holdings))| {
+            let median_of =
+                |f: fn(&Holding) -> f64| median(holdings.iter().copied().map(f).collect());
+            let losses = holdings.iter().filter(|holding| holding.gain < 0).count();
+            let summary = PostcodeSummary {
+                properties: count,
+                holdings: holdings.len(),
+                median_holding_years: median_of(|holding| holding.days as f64 / DAYS_PER_YEAR),
+                median_gain: median_of(|holding| holding.gain as f64),
+                median_annualised_return: Some(
+                    holdings
+                        .iter()
+                        .filter_map(|holding| holding.annualised_return)
+                        .collect::<Vec<f64>>(),
+                )
+                .filter(|returns| !returns.is_empty())
+                .map(median),
+                loss_share: match holdings.len() {
+                    0 => 0.0,
+                    len => losses as f64 / len as f64,
+                },
+            };
+            (code.to_string(), summary)
+        })
+        .collect();
+
+    History {
+        postcodes,
+        properties,
+    }
+}
+
+pub fn write_history(history: &History, path: &str) -> Result<(), Error> {
+    let out_file = File::create(path).map_err(Error::io(path))?;
+    serde_json::to_writer(out_file, history).map_err(Error::json(path))
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_unstable_by(f64::total_cmp);
+    find_median(&values)
+}
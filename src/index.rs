@@ -0,0 +1,341 @@
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+};
+
+use crate::{error::Error, sales::SalesHistory, stats::find_median, Grouping, PropertyType};
+
+/// The index of every series is calculated for the sales in all the selected regions together,
+/// under this name, as well as for each region.
+const ALL_REGIONS: &str = "all";
+
+/// Two consecutive sales of the same property, in different periods.
+#[derive(Debug, Clone)]
+struct SalePair {
+    first: NaiveDate, // start of the period of the first sale
+    second: NaiveDate,
+    log_change: f64, // ln(second price / first price)
+    days: f64,       // time between the sales, which the variance of the change grows with
+}
+
+/// Sales of a region and property type, which an index series is calculated from.
+#[derive(Debug, Default)]
+struct SeriesSales {
+    prices: BTreeMap<NaiveDate, Vec<i32>>, // every sale, by the start of its period
+    pairs: Vec<SalePair>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IndexSeries {
+    region: String,
+    property_type: PropertyType,
+    pairs: usize,
+    periods: Vec<IndexPeriod>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IndexPeriod {
+    start: NaiveDate,
+    end: NaiveDate,     // inclusive
+    index: Option<f32>, // 100 in the first period with repeat sales, none if not connected to it
+    sales: usize,
+    median: f32,
+}
+
+/// Calculates a repeat-sales price index per region and property type, Case-Shiller style:
+/// the changes in log price between consecutive sales of the same property are regressed
+/// on period dummies, weighting the pairs by how much their changes are expected to vary
+/// with the time between the sales.
+pub fn repeat_sales_index(history: SalesHistory, grouping: &Grouping) -> Vec<IndexSeries> {
+    let regions: Vec<&str> = [ALL_REGIONS]
+        .into_iter()
+        .chain(grouping.regions.iter().map(|region| region.name.as_str()))
+        .collect();
+    let mut series: BTreeMap<(usize, PropertyType), SeriesSales> = BTreeMap::new();
+    for (key, sales) in history.into_sorted() {
+        let property_regions: Vec<usize> = (0..regions.len())
+            .filter(|&index| index == 0 || grouping.regions[index - 1].contains(&key.postcode))
+            .collect();
+        let start = |date| grouping.period.start(date, grouping.origin);
+        for &region in &property_regions {
+            for sale in &sales {
+                let series_sales = series.entry((region, sale.property_type)).or_default();
+                series_sales
+                    .prices
+                    .entry(start(sale.date))
+                    .or_default()
+                    .push(sale.price);
+            }
+            for pair in sales.windows(2) {
+                let (first, second) = (pair[0], pair[1]);
+                // A change of property type means the property was changed too much to compare.
+                if first.property_type != second.property_type
+                    || start(first.date) == start(second.date)
+                    || first.price <= 0
+                    || second.price <= 0
+                {
+                    continue;
+                }
+                let series_sales = series.entry((region, second.property_type)).or_default();
+                series_sales.pairs.push(SalePair {
+                    first: start(first.date),
+                    second: start(second.date),
+                    log_change: (second.price as f64 / first.price as f64).ln(),
+                    days: (second.date - first.date).num_days() as f64,
+                });
+            }
+        }
+    }
+
+    series
+        .into_iter()
+        .map(|((region, property_type), mut sales)| {
+            let levels = fit_index(&sales.pairs).unwrap_or_default();
+            IndexSeries {
+                region: regions[region].to_string(),
+                property_type,
+                pairs: sales.pairs.len(),
+                periods: sales
+                    .prices
+                    .iter_mut()
+                    .map(|(&start, prices)| {
+                        prices.sort_unstable();
+                        IndexPeriod {
+                            start,
+                            end: grouping.period.end(start),
+                            index: levels.get(&start).map(|level| (100.0 * level.exp()) as f32),
+                            sales: prices.len(),
+                            median: find_median(prices) as f32,
+                        }
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+pub fn write_index(series: &[IndexSeries], path: &str) -> Result<(), Error> {
+    let out_file = File::create(path).map_err(Error::io(path))?;
+    serde_json::to_writer(out_file, series).map_err(Error::json(path))
+}
+
+/// Log price level of every period connected by the pairs to the first period, relative to it.
+/// Periods that are only connected to each other are left out, as their levels can't be
+/// compared to the first period. None if there are too few pairs for the levels to be determined.
+fn fit_index(pairs: &[SalePair]) -> Option<BTreeMap<NaiveDate, f64>> {
+    let pairs = &connected_to_first(pairs)[..];
+    let mut periods: Vec<NaiveDate> = pairs
+        .iter()
+        .flat_map(|pair| [pair.first, pair.second])
+        .collect();
+    periods.sort_unstable();
+    periods.dedup();
+    if periods.len() < 2 {
+        return None;
+    }
+    let column = |date: NaiveDate| periods.binary_search(&date).ok();
+
+    // Ordinary least squares first, to estimate how the variance of the errors
+    // grows with the time between the sales.
+    let ols = solve_pairs(pairs, &vec![1.0; pairs.len()], &column, periods.len() - 1)?;
+    let level = |levels: &[f64], date| match column(date) {
+        Some(0) | None => 0.0,
+        Some(index) => levels[index - 1],
+    };
+    let squared_errors: Vec<f64> = pairs
+        .iter()
+        .map(|pair| {
+            let error = pair.log_change - (level(&ols, pair.second) - level(&ols, pair.first));
+            error * error
+        })
+        .collect();
+    let days: Vec<f64> = pairs.iter().map(|pair| pair.days).collect();
+    let (intercept, slope) = linear_fit(&days, &squared_errors);
+    let variances: Vec<f64> = days.iter().map(|days| intercept + slope * days).collect();
+
+    // Weighted least squares, trusting the pairs with shorter holding periods more.
+    // The unweighted fit is kept if the variance doesn't fit a positive line.
+    let levels = if variances.iter().all(|variance| *variance > 0.0) {
+        let weights: Vec<f64> = variances.iter().map(|variance| 1.0 / variance).collect();
+        solve_pairs(pairs, &weights, &column, periods.len() - 1)?
+    } else {
+        ols
+    };
+
+    Some(
+        periods
+            .iter()
+            .map(|&date| (date, level(&levels, date)))
+            .collect(),
+    )
+}
+
+/// The pairs of the periods connected to the first period, directly or through other periods.
+fn connected_to_first(pairs: &[SalePair]) -> Vec<SalePair> {
+    let Some(first) = pairs.iter().map(|pair| pair.first.min(pair.second)).min() else {
+        return Vec::new();
+    };
+    let mut connected = BTreeSet::from([first]);
+    // Every round connects at least one more period, until no pair adds any.
+    loop {
+        let mut added = false;
+        for pair in pairs {
+            match (
+                connected.contains(&pair.first),
+                connected.contains(&pair.second),
+            ) {
+                (true, false) => added |= connected.insert(pair.second),
+                (false, true) => added |= connected.insert(pair.first),
+                _ => {}
+            }
+        }
+        if !added {
+            break;
+        }
+    }
+    pairs
+        .iter()
+        .filter(|pair| connected.contains(&pair.first))
+        .cloned()
+        .collect()
+}
+
+/// Solves the weighted normal equations of the pairs, with a dummy for every period
+/// but the first, which is the base of the index.
+fn solve_pairs(
+    pairs: &[SalePair],
+    weights: &[f64],
+    column: &impl Fn(NaiveDate) -> Option<usize>,
+    unknowns: usize,
+) -> Option<Vec<f64>> {
+    let mut matrix = vec![vec![0.0; unknowns]; unknowns];
+    let mut rhs = vec![0.0; unknowns];
+    for (pair, weight) in pairs.iter().zip(weights) {
+        // The row of the design matrix has -1 for the period of the first sale
+        // and +1 for the period of the second one.
+        let terms = [
+            (column(pair.first).unwrap_or(0), -1.0),
+            (column(pair.second).unwrap_or(0), 1.0),
+        ];
+        for (row, row_sign) in terms {
+            if row == 0 {
+                continue;
+            }
+            rhs[row - 1] += weight * row_sign * pair.log_change;
+            for (col, col_sign) in terms {
+                if col != 0 {
+                    matrix[row - 1][col - 1] += weight * row_sign * col_sign;
+                }
+            }
+        }
+    }
+    solve(matrix, rhs)
+}
+
+/// Gaussian elimination with partial pivoting. None if the matrix is singular,
+/// e.g. when some of the periods are not connected to the base period by any pairs.
+fn solve(mut matrix: Vec<Vec<f64>>, mut rhs: Vec<f64>) -> Option<Vec<f64>> {
+    let size = rhs.len();
+    for col in 0..size {
+        let pivot =
+            (col..size).max_by(|&a, &b| matrix[a][col].abs().total_cmp(&matrix[b][col].abs()))?;
+        if matrix[pivot][col].abs() < 1e-9 {
+            return None;
+        }
+        matrix.swap(col, pivot);
+        rhs.swap(col, pivot);
+        let (upper, lower) = matrix.split_at_mut(col + 1);
+        let pivot_row = &upper[col];
+        for (offset, row) in lower.iter_mut().enumerate() {
+            let factor = row[col] / pivot_row[col];
+            if factor == 0.0 {
+                continue;
+            }
+            for (value, pivot_value) in row[col..].iter_mut().zip(&pivot_row[col..]) {
+                *value -= factor * pivot_value;
+            }
+            rhs[col + 1 + offset] -= factor * rhs[col];
+        }
+    }
+    let mut solution = vec![0.0; size];
+    for row in (0..size).rev() {
+        let sum: f64 = (row + 1..size).map(|k| matrix[row][k] * solution[k]).sum();
+        solution[row] = (rhs[row] - sum) / matrix[row][row];
+    }
+    Some(solution)
+}
+
+/// Least squares line through the points, as (intercept, slope).
+fn linear_fit(xs: &[f64], ys: &[f64]) -> (f64, f64) {
+    let count = xs.len() as f64;
+    let mean_x = xs.iter().sum::<f64>() / count;
+    let mean_y = ys.iter().sum::<f64>() / count;
+    let covariance: f64 = xs
+        .iter()
+        .zip(ys)
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = xs.iter().map(|x| (x - mean_x) * (x - mean_x)).sum();
+    if variance > 0.0 {
+        let slope = covariance / variance;
+        (mean_y - slope * mean_x, slope)
+    } else {
+        (mean_y, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn year(year: i32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, 1, 1).unwrap()
+    }
+
+    fn pair(first: i32, second: i32, ratio: f64) -> SalePair {
+        SalePair {
+            first: year(first),
+            second: year(second),
+            log_change: ratio.ln(),
+            days: (year(second) - year(first)).num_days() as f64,
+        }
+    }
+
+    #[test]
+    fn solve_with_pivoting() {
+        // y = 2, x = 3, with a zero on the diagonal that has to be pivoted around.
+        let solution = solve(vec![vec![0.0, 1.0], vec![1.0, 0.0]], vec![2.0, 3.0]).unwrap();
+        assert_eq!(solution, vec![3.0, 2.0]);
+        // 2x + y = 5, x + 3y = 10.
+        let solution = solve(vec![vec![2.0, 1.0], vec![1.0, 3.0]], vec![5.0, 10.0]).unwrap();
+        assert!((solution[0] - 1.0).abs() < 1e-9 && (solution[1] - 3.0).abs() < 1e-9);
+        assert!(solve(vec![vec![1.0, 1.0], vec![1.0, 1.0]], vec![1.0, 2.0]).is_none());
+    }
+
+    #[test]
+    fn fit_index_of_consistent_pairs() {
+        let pairs = [
+            pair(2015, 2016, 1.1),
+            pair(2016, 2017, 1.2),
+            pair(2015, 2017, 1.1 * 1.2),
+        ];
+        let levels = fit_index(&pairs).unwrap();
+        let expected = [(2015, 1.0), (2016, 1.1), (2017, 1.32)];
+        assert_eq!(levels.len(), expected.len());
+        for (period, ratio) in expected {
+            assert!((levels[&year(period)] - f64::ln(ratio)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn fit_index_leaves_out_periods_not_connected_to_the_first() {
+        let pairs = [pair(2015, 2016, 1.1), pair(2019, 2020, 1.3)];
+        let levels = fit_index(&pairs).unwrap();
+        assert_eq!(levels.len(), 2);
+        assert_eq!(levels[&year(2015)], 0.0);
+        assert!((levels[&year(2016)] - f64::ln(1.1)).abs() < 1e-9);
+        assert!(!levels.contains_key(&year(2019)) && !levels.contains_key(&year(2020)));
+    }
+}
@@ -0,0 +1,137 @@
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::error::Error;
+
+/// Reading from this path means reading from the standard input.
+pub const STDIN: &str = "-";
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const ZIP_MAGIC: &[u8] = &[0x50, 0x4b, 0x03, 0x04];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Zip,
+}
+
+impl Compression {
+    /// Detects the compression from the file extension, falling back to the magic bytes
+    /// at the start of the data for files without a known extension.
+    fn detect(path: &str, start: &[u8]) -> Compression {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            Some("zip") => Compression::Zip,
+            _ if start.starts_with(GZIP_MAGIC) => Compression::Gzip,
+            _ if start.starts_with(ZSTD_MAGIC) => Compression::Zstd,
+            _ if start.starts_with(ZIP_MAGIC) => Compression::Zip,
+            _ => Compression::None,
+        }
+    }
+}
+
+pub type Input = BufReader<Box<dyn Read + Send>>;
+
+/// Opens a file for reading, decompressing it on the fly if needed.
+pub fn open(path: &str) -> Result<Input, Error> {
+    if path == STDIN {
+        let mut reader = BufReader::new(io::stdin());
+        let start = reader.fill_buf().map_err(Error::io(path))?;
+        let reader: Box<dyn Read + Send> = match Compression::detect(path, start) {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Compression::Zstd => {
+                Box::new(zstd::Decoder::with_buffer(reader).map_err(Error::io(path))?)
+            }
+            Compression::Zip => {
+                return Err(Error::Input {
+                    path: path.to_string(),
+                    message: "zip archives can't be read from the standard input".to_string(),
+                })
+            }
+        };
+        return Ok(BufReader::new(reader));
+    }
+
+    let file = File::open(path).map_err(Error::io(path))?;
+    let reader: Box<dyn Read + Send> = match compression(path)? {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(file).map_err(Error::io(path))?),
+        Compression::Zip => open_zip(path, file)?,
+    };
+    Ok(BufReader::new(reader))
+}
+
+/// Detects the compression of a file (not the standard input).
+pub fn compression(path: &str) -> Result<Compression, Error> {
+    let mut start = Vec::with_capacity(ZSTD_MAGIC.len());
+    File::open(path)
+        .and_then(|file| file.take(ZSTD_MAGIC.len() as u64).read_to_end(&mut start))
+        .map_err(Error::io(path))?;
+    Ok(Compression::detect(path, &start))
+}
+
+/// Whether the input is an uncompressed file, which can be split up and read in parallel.
+pub fn is_splittable(path: &str) -> Result<bool, Error> {
+    Ok(path != STDIN && compression(path)? == Compression::None)
+}
+
+/// Reads the first line of the input, including the line break.
+pub fn read_first_line(input: &mut Input) -> Result<Vec<u8>, io::Error> {
+    let mut line = Vec::new();
+    input.read_until(b'\n', &mut line)?;
+    Ok(line)
+}
+
+/// Land Registry archives contain a single CSV file. Rather than keeping the archive around
+/// for the lifetime of the reader, the compressed data of the file is read directly.
+fn open_zip(path: &str, file: File) -> Result<Box<dyn Read + Send>, Error> {
+    let zip_error = |error: zip::result::ZipError| Error::Input {
+        path: path.to_string(),
+        message: error.to_string(),
+    };
+    let mut archive = ZipArchive::new(file).map_err(zip_error)?;
+    if archive.len() != 1 {
+        return Err(Error::Input {
+            path: path.to_string(),
+            message: format!(
+                "expected a single file in the archive, found {}",
+                archive.len()
+            ),
+        });
+    }
+    let (method, start, size) = {
+        let entry = archive.by_index(0).map_err(zip_error)?;
+        (
+            entry.compression(),
+            entry.data_start(),
+            entry.compressed_size(),
+        )
+    };
+
+    let mut file = archive.into_inner();
+    file.seek(SeekFrom::Start(start)).map_err(Error::io(path))?;
+    let data = file.take(size);
+    match method {
+        CompressionMethod::Stored => Ok(Box::new(data)),
+        CompressionMethod::Deflated => Ok(Box::new(DeflateDecoder::new(data))),
+        method => Err(Error::Input {
+            path: path.to_string(),
+            message: format!("unsupported compression method {}", method),
+        }),
+    }
+}
@@ -1,7 +1,51 @@
-use chrono::{Datelike, NaiveDate};
-use clap::Parser;
+mod address;
+mod bootstrap;
+mod cache;
+mod change;
+mod deflator;
+mod error;
+mod filter;
+mod fnv;
+mod history;
+mod index;
+mod input;
+mod outlier;
+mod period;
+mod postcode;
+mod profile;
+mod region;
+mod rolling;
+mod sales;
+mod sample;
+mod stats;
+
+use address::Address;
+use bootstrap::Bootstrap;
+use change::ChangeTracker;
+use chrono::NaiveDate;
+use clap::{
+    parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
+use deflator::Deflator;
+use error::{Error, RecordError};
+use filter::{Filter, PostcodePattern};
+use outlier::{OutlierRule, OutlierRules, SaleId};
+use period::Period;
+use postcode::Postcode;
+use profile::Profile;
+use region::Region;
+use sales::SalesHistory;
+use sample::{Fallback, MinSales};
 use serde::Serialize;
-use std::{collections::HashMap, error::Error, fs::File, io::Write, ops::Range};
+use stats::{PriceBucket, Statistics};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write},
+    ops::{Range, RangeInclusive},
+    process, thread,
+};
 
 // https://www.gov.uk/guidance/about-the-price-paid-data#explanations-of-column-headers-in-the-ppd
 
@@ -12,18 +56,499 @@ use std::{collections::HashMap, error::Error, fs::File, io::Write, ops::Range};
 
 const DEFAULT_FILE_NAME: &str = "pp-complete.csv";
 const DATE_FORMAT: &str = "%Y-%m-%d %H:%M";
+const COLUMN_COUNT: usize = 16;
+const COLUMN_NAMES: [&str; COLUMN_COUNT] = [
+    "transaction id",
+    "price",
+    "date of transfer",
+    "postcode",
+    "property type",
+    "old/new",
+    "duration",
+    "PAON",
+    "SAON",
+    "street",
+    "locality",
+    "town/city",
+    "district",
+    "county",
+    "PPD category type",
+    "record status",
+];
+const DEFAULT_QUARANTINE_FILE_NAME: &str = "quarantine.csv";
+const DEFAULT_CONFLICTS_FILE_NAME: &str = "conflicts.csv";
+const DEFAULT_OUTPUT_FILE_NAME: &str = "stats.json";
+const DEFAULT_HISTORY_FILE_NAME: &str = "history.json";
 
+// The list options are global, so without subcommand precedence they would take
+// the name of a subcommand after them as one of their values.
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_precedence_over_arg = true)]
 struct Args {
-    /// Name of the person to greet
-    #[arg(short, long, default_value_t = DEFAULT_FILE_NAME.to_string())]
-    file: String,
-    // #[arg(short, long, default_value_t = 1)]
-    // count: u8,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Name of a profile in the configuration file to take the options from.
+    /// Options given on the command line override the ones in the profile.
+    #[arg(short, long, global = true)]
+    profile: Option<String>,
+    /// Path to the configuration file with the profiles
+    #[arg(long, default_value_t = profile::DEFAULT_CONFIG_FILE_NAME.to_string(), global = true)]
+    config: String,
+    /// Paths or glob patterns of the Price Paid CSV files (e.g. pp-complete.csv or "pp-20*.csv"),
+    /// optionally compressed with gzip, zstd or zip, or "-" to read from the standard input.
+    /// Records are de-duplicated by transaction id, the first file listed takes precedence.
+    #[arg(short, long = "file", num_args = 1.., default_values_t = [DEFAULT_FILE_NAME.to_string()], global = true)]
+    files: Vec<String>,
+    /// Path to a monthly change file (e.g. pp-monthly-update.csv) to apply on top of the base file
+    #[arg(short, long, global = true)]
+    update: Option<String>,
+    /// Number of threads used to parse the CSV file (defaults to the number of CPUs)
+    #[arg(short, long, global = true)]
+    threads: Option<usize>,
+    /// Whether the CSV files start with a header row (detected from the first row if not set).
+    /// The official Price Paid files don't have one.
+    #[arg(long, global = true)]
+    has_header: Option<bool>,
+    /// Skip the records that fail to parse instead of stopping, writing them to the quarantine file
+    #[arg(short, long, global = true)]
+    lenient: bool,
+    /// Where the records skipped in lenient mode are written to
+    #[arg(long, default_value_t = DEFAULT_QUARANTINE_FILE_NAME.to_string(), global = true)]
+    quarantine: String,
+    /// Where the records that have the same transaction id but different data in different files are listed
+    #[arg(long, default_value_t = DEFAULT_CONFLICTS_FILE_NAME.to_string(), global = true)]
+    conflicts: String,
+    /// Parse the CSV files even if they have an up to date cache
+    #[arg(long, global = true)]
+    no_cache: bool,
+    /// Only include sales on or after this date (YYYY-MM-DD)
+    #[arg(long, default_value = "2021-01-01", global = true)]
+    from: Option<NaiveDate>,
+    /// Only include sales on or before this date (YYYY-MM-DD)
+    #[arg(long, global = true)]
+    to: Option<NaiveDate>,
+    /// Only include sales of these tenures (all if empty)
+    #[arg(long, value_delimiter = ',', num_args = 0.., default_values = ["leasehold"], global = true)]
+    tenure: Vec<DurationOfTransfer>,
+    /// Only include sales of these property types (all if empty)
+    #[arg(long = "type", value_delimiter = ',', num_args = 0.., default_values = ["detached", "semi-detached", "terraced", "flat"], global = true)]
+    property_types: Vec<PropertyType>,
+    /// Only include sales of new or old builds (both if empty)
+    #[arg(long = "age", value_delimiter = ',', num_args = 0.., global = true)]
+    property_ages: Vec<PropertyAge>,
+    /// Only include sales of these PPD category types (all if empty)
+    #[arg(long = "category", value_delimiter = ',', num_args = 0.., global = true)]
+    ppd_categories: Vec<PpdCategory>,
+    /// Only include sales for at least this price
+    #[arg(long, global = true)]
+    min_price: Option<i32>,
+    /// Only include sales for at most this price
+    #[arg(long, global = true)]
+    max_price: Option<i32>,
+    /// Only include sales in these postcode areas, districts, sectors or units (e.g. "SE16,E14 9"),
+    /// or with postcodes starting with a prefix ending with "*" (e.g. "SE1*", or "*" for all).
+    /// Defaults to the "desirable" region if no regions are selected either.
+    #[arg(long, value_delimiter = ',', num_args = 0.., global = true)]
+    postcodes: Vec<String>,
+    /// Only include sales in these named regions, which are also aggregated as a whole.
+    /// Either built-in (london, inner-london, desirable) or defined in the region file.
+    #[arg(long = "region", value_delimiter = ',', num_args = 0.., global = true)]
+    regions: Vec<String>,
+    /// TOML or JSON file with named lists of postcode districts, sectors or prefixes.
+    /// Every region in the file is used if none are selected with --region.
+    #[arg(long, global = true)]
+    region_file: Option<String>,
+    /// Sales for at least this price are listed individually in the sector stats
+    #[arg(long, default_value_t = *DEFAULT_LISTED_PRICES.start())]
+    list_min_price: i32,
+    /// Sales for at most this price are listed individually in the sector stats
+    #[arg(long, default_value_t = *DEFAULT_LISTED_PRICES.end())]
+    list_max_price: i32,
+    /// Length of the periods the stats are calculated for: year, quarter, month, tax-year
+    /// (6 April to 5 April), or a number of days, months or years (e.g. 90d, 6m or 2y)
+    /// counted from the --from date
+    #[arg(long, default_value_t = Period::Year)]
+    period: Period,
+    /// Calculate the stats of every period over this many trailing periods instead
+    /// (e.g. --period month --rolling 12). Individual sales are not listed.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    rolling: Option<u32>,
+    /// Percentiles of the prices to include in the stats
+    #[arg(long, value_delimiter = ',', num_args = 0.., default_values_t = stats::DEFAULT_QUANTILES)]
+    quantiles: Vec<f64>,
+    /// Percentage of the lowest and of the highest prices left out of the trimmed mean
+    #[arg(long, default_value_t = stats::DEFAULT_TRIM)]
+    trim: f64,
+    /// Number of price histogram bins per tenfold increase in price (0 for no histogram)
+    #[arg(long, default_value_t = stats::DEFAULT_HISTOGRAM_BINS)]
+    histogram_bins: u32,
+    /// Also calculate a repeat-sales price index per region and property type,
+    /// from the sales of the same properties in different periods, and write it to this file
+    #[arg(long)]
+    index: Option<String>,
+    /// Add bootstrap confidence intervals of the median and quantiles to the stats, and of the
+    /// changes between periods, from this many resamples of the prices of every bucket (e.g. 1000)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    bootstrap: Option<u32>,
+    /// Percentage of the resamples the bootstrap confidence intervals cover
+    #[arg(long, default_value_t = bootstrap::DEFAULT_CONFIDENCE)]
+    confidence: f64,
+    /// Seed of the random resampling, which makes the bootstrap intervals reproducible
+    #[arg(long, default_value_t = bootstrap::DEFAULT_SEED)]
+    seed: u64,
+    /// Leave sales for less than this price out of the stats, e.g. nominal £1 transfers
+    #[arg(long)]
+    outlier_floor: Option<i32>,
+    /// Leave sales for more than this price out of the stats
+    #[arg(long)]
+    outlier_ceiling: Option<i32>,
+    /// Leave the additional price paid sales (category B), such as repossessions and bulk
+    /// transfers, out of the stats. Unlike --category, the sales left out are reported.
+    #[arg(long)]
+    exclude_category_b: bool,
+    /// Leave sales more than this many interquartile ranges below P25 or above P75
+    /// of their bucket out of the stats (e.g. 1.5). The transaction ids of the sales left out
+    /// by any rule are listed in the sector stats only, and not with --rolling
+    #[arg(long)]
+    iqr_fence: Option<f64>,
+    /// Leave sales with a modified z-score, based on the median absolute deviation of
+    /// their bucket, above this out of the stats (e.g. 3.5)
+    #[arg(long)]
+    mad_threshold: Option<f64>,
+    /// Buckets with fewer sales than this are marked as low sample, or fall back
+    /// to the stats of their parents (see --fallback)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    min_sales: Option<u64>,
+    /// What the buckets with fewer sales than --min-sales report
+    #[arg(long, value_enum, default_value_t = Fallback::Mark)]
+    fallback: Fallback,
+    /// Changes of the median price between periods are flagged as low sample
+    /// if either period has fewer sales than this
+    #[arg(long, default_value_t = change::DEFAULT_MIN_CHANGE_SALES)]
+    change_min_sales: usize,
+    /// Also calculate the stats of the prices adjusted for inflation, using the monthly consumer
+    /// price index (e.g. CPIH) in this CSV file, with the month (2024-01 or 2024 JAN)
+    /// in the first column and the index value in the second one
+    #[arg(long)]
+    cpi: Option<String>,
+    /// Month whose money the inflation-adjusted prices are in (defaults to the last month
+    /// in the --cpi file)
+    #[arg(long, value_parser = deflator::parse_month)]
+    cpi_month: Option<NaiveDate>,
+    /// Where the stats are written to
+    #[arg(short, long, default_value_t = DEFAULT_OUTPUT_FILE_NAME.to_string())]
+    output: String,
 }
 
-#[derive(Hash, Clone, Copy, Eq, PartialEq, Debug, Serialize)]
+/// Profiles write dates as TOML dates, e.g. `from = 2021-01-01`.
+fn to_date(value: Option<toml::value::Datetime>) -> Result<Option<NaiveDate>, Error> {
+    value
+        .map(|value| {
+            value
+                .to_string()
+                .parse()
+                .map_err(|_| Error::Config(format!("invalid date {} in the profile", value)))
+        })
+        .transpose()
+}
+
+fn parse_period(value: Option<String>) -> Result<Option<Period>, Error> {
+    value
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|error| Error::Config(format!("{} in the profile", error)))
+        })
+        .transpose()
+}
+
+/// Parses profile values the same way as the command-line option values.
+fn parse_values<T: ValueEnum>(values: Option<Vec<String>>) -> Result<Option<Vec<T>>, Error> {
+    values
+        .map(|values| {
+            values
+                .iter()
+                .map(|value| {
+                    T::from_str(value, false)
+                        .map_err(|error| Error::Config(format!("{} in the profile", error)))
+                })
+                .collect()
+        })
+        .transpose()
+}
+
+fn parse_value<T: ValueEnum>(value: Option<String>) -> Result<Option<T>, Error> {
+    Ok(parse_values(value.map(|value| vec![value]))?.and_then(|mut values| values.pop()))
+}
+
+impl Args {
+    /// Takes the options that were not given on the command line from the profile.
+    fn apply_profile(&mut self, profile: Profile, matches: &ArgMatches) -> Result<(), Error> {
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        fn set<T>(given: bool, field: &mut T, value: Option<T>) {
+            if let (false, Some(value)) = (given, value) {
+                *field = value;
+            }
+        }
+
+        set(given("files"), &mut self.files, profile.files);
+        set(given("update"), &mut self.update, profile.update.map(Some));
+        set(
+            given("threads"),
+            &mut self.threads,
+            profile.threads.map(Some),
+        );
+        set(
+            given("has_header"),
+            &mut self.has_header,
+            profile.has_header.map(Some),
+        );
+        set(given("lenient"), &mut self.lenient, profile.lenient);
+        set(
+            given("quarantine"),
+            &mut self.quarantine,
+            profile.quarantine,
+        );
+        set(given("conflicts"), &mut self.conflicts, profile.conflicts);
+        set(given("no_cache"), &mut self.no_cache, profile.no_cache);
+        set(
+            given("from"),
+            &mut self.from,
+            to_date(profile.from)?.map(Some),
+        );
+        set(given("to"), &mut self.to, to_date(profile.to)?.map(Some));
+        set(
+            given("tenure"),
+            &mut self.tenure,
+            parse_values(profile.tenure)?,
+        );
+        set(
+            given("property_types"),
+            &mut self.property_types,
+            parse_values(profile.property_types)?,
+        );
+        set(
+            given("property_ages"),
+            &mut self.property_ages,
+            parse_values(profile.property_ages)?,
+        );
+        set(
+            given("ppd_categories"),
+            &mut self.ppd_categories,
+            parse_values(profile.ppd_categories)?,
+        );
+        set(
+            given("min_price"),
+            &mut self.min_price,
+            profile.min_price.map(Some),
+        );
+        set(
+            given("max_price"),
+            &mut self.max_price,
+            profile.max_price.map(Some),
+        );
+        set(given("postcodes"), &mut self.postcodes, profile.postcodes);
+        set(given("regions"), &mut self.regions, profile.regions);
+        set(
+            given("region_file"),
+            &mut self.region_file,
+            profile.region_file.map(Some),
+        );
+        set(
+            given("list_min_price"),
+            &mut self.list_min_price,
+            profile.list_min_price,
+        );
+        set(
+            given("list_max_price"),
+            &mut self.list_max_price,
+            profile.list_max_price,
+        );
+        set(
+            given("period"),
+            &mut self.period,
+            parse_period(profile.period)?,
+        );
+        set(
+            given("rolling"),
+            &mut self.rolling,
+            profile.rolling.map(Some),
+        );
+        set(given("quantiles"), &mut self.quantiles, profile.quantiles);
+        set(given("trim"), &mut self.trim, profile.trim);
+        set(
+            given("histogram_bins"),
+            &mut self.histogram_bins,
+            profile.histogram_bins,
+        );
+        set(given("index"), &mut self.index, profile.index.map(Some));
+        set(
+            given("bootstrap"),
+            &mut self.bootstrap,
+            profile.bootstrap.map(Some),
+        );
+        set(
+            given("confidence"),
+            &mut self.confidence,
+            profile.confidence,
+        );
+        set(given("seed"), &mut self.seed, profile.seed);
+        set(
+            given("outlier_floor"),
+            &mut self.outlier_floor,
+            profile.outlier_floor.map(Some),
+        );
+        set(
+            given("outlier_ceiling"),
+            &mut self.outlier_ceiling,
+            profile.outlier_ceiling.map(Some),
+        );
+        set(
+            given("exclude_category_b"),
+            &mut self.exclude_category_b,
+            profile.exclude_category_b,
+        );
+        set(
+            given("iqr_fence"),
+            &mut self.iqr_fence,
+            profile.iqr_fence.map(Some),
+        );
+        set(
+            given("mad_threshold"),
+            &mut self.mad_threshold,
+            profile.mad_threshold.map(Some),
+        );
+        set(
+            given("min_sales"),
+            &mut self.min_sales,
+            profile.min_sales.map(Some),
+        );
+        set(
+            given("fallback"),
+            &mut self.fallback,
+            parse_value(profile.fallback)?,
+        );
+        set(
+            given("change_min_sales"),
+            &mut self.change_min_sales,
+            profile.change_min_sales,
+        );
+        set(given("cpi"), &mut self.cpi, profile.cpi.map(Some));
+        set(
+            given("cpi_month"),
+            &mut self.cpi_month,
+            profile
+                .cpi_month
+                .map(|month| {
+                    deflator::parse_month(&month)
+                        .map_err(|error| Error::Config(format!("{} in the profile", error)))
+                })
+                .transpose()?
+                .map(Some),
+        );
+        set(given("output"), &mut self.output, profile.output);
+
+        Ok(())
+    }
+
+    /// Without the stats, only the sales of the properties are kept, for their history.
+    fn grouping(&self, stats: bool) -> Result<Grouping, Error> {
+        // The command line checks these already, but the profiles don't.
+        let counts = [
+            ("rolling", self.rolling.map(u64::from)),
+            ("bootstrap", self.bootstrap.map(u64::from)),
+            ("min-sales", self.min_sales),
+        ];
+        if let Some((name, _)) = counts.iter().find(|(_, count)| *count == Some(0)) {
+            return Err(Error::Config(format!("{} has to be at least 1", name)));
+        }
+        Ok(Grouping {
+            regions: region::resolve(&self.regions, self.region_file.as_deref())?,
+            listed_prices: self.list_min_price..=self.list_max_price,
+            period: self.period,
+            rolling: self.rolling,
+            min_sales: self.min_sales.map(|count| MinSales {
+                count: count as usize,
+                fallback: self.fallback,
+            }),
+            change_min_sales: self.change_min_sales,
+            stats,
+            repeat_sales: self.index.is_some() || !stats,
+            statistics: Statistics::new(
+                self.quantiles.clone(),
+                self.trim,
+                self.histogram_bins,
+                OutlierRules::new(
+                    self.outlier_floor,
+                    self.outlier_ceiling,
+                    self.exclude_category_b,
+                    self.iqr_fence,
+                    self.mad_threshold,
+                )?,
+                self.bootstrap
+                    .map(|resamples| Bootstrap::new(resamples as usize, self.confidence, self.seed))
+                    .transpose()?,
+            )?,
+            origin: self.from.unwrap_or(period::DEFAULT_ORIGIN),
+            deflator: self
+                .cpi
+                .as_deref()
+                .filter(|_| stats)
+                .map(|path| {
+                    let deflator = Deflator::load(path, self.cpi_month)?;
+                    let from = self.from.unwrap_or(period::DEFAULT_ORIGIN);
+                    deflator.check_range(path, from, self.to)?;
+                    Ok::<_, Error>(deflator)
+                })
+                .transpose()?,
+        })
+    }
+
+    fn filter(&self, regions: &[Region]) -> Filter {
+        let mut postcodes: Vec<PostcodePattern> = self
+            .postcodes
+            .iter()
+            .map(|postcode| PostcodePattern::parse(postcode))
+            .collect();
+        postcodes.extend(regions.iter().flat_map(|region| region.patterns.clone()));
+        if postcodes.is_empty() {
+            postcodes = region::DESIRABLE_POSTCODES
+                .iter()
+                .map(|postcode| PostcodePattern::parse(postcode))
+                .collect();
+        }
+
+        Filter {
+            from: self.from,
+            to: self.to,
+            durations: self.tenure.clone(),
+            property_types: self.property_types.clone(),
+            property_ages: self.property_ages.clone(),
+            ppd_categories: self.ppd_categories.clone(),
+            min_price: self.min_price,
+            max_price: self.max_price,
+            postcodes,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Parse the input files once and save the records to a binary cache next to each file,
+    /// which is read instead of the CSV file by the following runs
+    Ingest,
+    /// Follow the properties sold more than once through their sales, with the holding period,
+    /// gain and annualised return (for holdings of a year or more) between consecutive sales,
+    /// summarised per postcode.
+    /// Covers every year unless --from is given.
+    History {
+        /// Where the history is written to
+        #[arg(short, long, default_value_t = DEFAULT_HISTORY_FILE_NAME.to_string())]
+        output: String,
+    },
+}
+
+#[derive(Hash, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Debug, Serialize, ValueEnum)]
 enum PropertyType {
     Detached,
     SemiDetached,
@@ -32,250 +557,1198 @@ enum PropertyType {
     Other,
 }
 
-#[derive(Hash, Clone, Copy, Eq, PartialEq, Debug, Serialize)]
+#[derive(Hash, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Debug, Serialize, ValueEnum)]
 enum PropertyAge {
     New,
     Old,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Hash, Clone, Copy, Eq, PartialEq, Debug, Serialize, ValueEnum)]
 enum DurationOfTransfer {
     Freehold,
     Leasehold,
 }
 
-#[derive(Debug)]
+#[derive(Hash, Clone, Copy, Eq, PartialEq, Debug, Serialize, ValueEnum)]
+enum PpdCategory {
+    #[value(alias = "a")]
+    Standard, // full market value sales
+    #[value(alias = "b")]
+    Additional, // repossessions, buy-to-lets, transfers to non-private individuals, etc.
+}
+
+#[derive(Hash, Clone, Copy, Eq, PartialEq, Debug, Serialize)]
+enum RecordStatus {
+    Added,
+    Changed,
+    Deleted,
+}
+
+/// A single Price Paid record with every column of the dataset.
+#[allow(dead_code)] // not every column is used by the current analysis
+#[derive(Debug, Clone, Hash)]
 struct Entry {
+    id: String, // transaction unique identifier, used to match records from the monthly change files
     price: i32,
     date: NaiveDate,
-    address: String,
-    postcode: String, // postcodes can be reallocated and these changes are not reflected in the Price Paid Dataset
+    postcode: Option<Postcode>, // postcodes can be reallocated and these changes are not reflected in the Price Paid Dataset
     property_type: PropertyType,
     property_age: PropertyAge,
-    // duration: DurationOfTransfer,
+    duration: DurationOfTransfer,
+    paon: String, // primary addressable object name, e.g. house number or name
+    saon: String, // secondary addressable object name, e.g. flat number
+    street: String,
+    locality: String,
+    city: String,
+    district: String,
+    county: String,
+    ppd_category: PpdCategory,
+    record_status: RecordStatus,
 }
 
-#[derive(Debug, Serialize)]
-struct YearEntry {
-    #[serde(skip_serializing)]
-    properties: HashMap<PropertyType, HashMap<PropertyAge, Vec<Property>>>,
-    year: i32,
+impl Entry {
+    fn from_record(record: &csv::StringRecord) -> Result<Entry, RecordError> {
+        check_column_count(record.len())?;
+        let field = |index: usize| record.get(index).unwrap_or("");
+
+        Ok(Entry {
+            id: field(0).to_string(),
+            price: field(1)
+                .parse()
+                .map_err(|error| RecordError::column(1, field(1), error))?,
+            date: NaiveDate::parse_from_str(field(2), DATE_FORMAT)
+                .map_err(|error| RecordError::column(2, field(2), error))?,
+            postcode: match field(3).trim() {
+                "" => None, // some records, e.g. of new builds, have no postcode
+                postcode => Some(
+                    postcode
+                        .parse()
+                        .map_err(|error| RecordError::column(3, field(3), error))?,
+                ),
+            },
+            property_type: to_property_type(field(4)),
+            property_age: to_property_age(field(5)),
+            duration: to_duration_of_transfer(field(6)),
+            paon: field(7).to_string(),
+            saon: field(8).to_string(),
+            street: field(9).to_string(),
+            locality: field(10).to_string(),
+            city: field(11).to_string(),
+            district: field(12).to_string(),
+            county: field(13).to_string(),
+            ppd_category: to_ppd_category(field(14)),
+            record_status: to_record_status(field(15)),
+        })
+    }
+
+    fn address(&self) -> String {
+        Address::from_entry(self).to_string()
+    }
 }
 
-#[derive(Debug, Default, Serialize)]
-struct PriceBucket {
-    count: usize,
-    median: f32,
-    range: Range<i32>,
-    properties: Vec<Property>,
+/// Sales with prices in this range are listed in the output individually, unless set otherwise.
+const DEFAULT_LISTED_PRICES: RangeInclusive<i32> = 300_000..=800_000;
+
+/// How the entries that pass the filters are grouped, and what is kept of them.
+#[derive(Debug)]
+struct Grouping {
+    regions: Vec<Region>, // aggregated as a whole, in addition to the postcode areas
+    listed_prices: RangeInclusive<i32>, // sales in this range are listed in the sector stats
+    period: Period,
+    rolling: Option<u32>, // number of trailing periods the stats of each period cover
+    statistics: Statistics,
+    min_sales: Option<MinSales>, // below which buckets are marked or fall back to their parents
+    change_min_sales: usize,     // fewer sales in either period flag a change as low sample
+    stats: bool,                 // whether to aggregate the stats of the periods
+    repeat_sales: bool, // whether to keep the sales of every property, for the repeat-sales index
+    origin: NaiveDate,  // where custom periods are counted from
+    deflator: Option<Deflator>, // for the stats of the inflation-adjusted prices
 }
 
-#[derive(Debug, Default, Serialize, Clone)]
-struct Property {
-    address: String,
-    price: i32,
+/// Running state of a single bucket. Every price is kept (the median needs them all),
+/// but addresses are only kept for the sales that end up being listed in the output.
+#[derive(Debug, Default)]
+struct Accumulator {
+    prices: Vec<i32>,
+    real_prices: Vec<i32>, // in the order of the prices, adjusted for inflation if an index is given
+    ids: Vec<SaleId>, // in the order of the prices, when listed sales can be outside the fences
+    excluded: BTreeMap<OutlierRule, usize>, // sales left out by the rules checked for every sale
+    excluded_ids: Vec<String>,
+    properties: Vec<Property>,
 }
 
-fn to_price_bucket(properties: &mut Vec<Property>) -> PriceBucket {
-    let mut result = PriceBucket::default();
+impl Accumulator {
+    fn add(
+        &mut self,
+        entry: &Entry,
+        real_price: Option<i32>,
+        outliers: &OutlierRules,
+        listed_prices: Option<&RangeInclusive<i32>>,
+    ) {
+        if let Some(rule) = outliers.check(entry) {
+            *self.excluded.entry(rule).or_default() += 1;
+            if listed_prices.is_some() {
+                self.excluded_ids.push(entry.id.clone());
+            }
+            return;
+        }
+        self.prices.push(entry.price);
+        self.real_prices.extend(real_price);
+        if listed_prices.is_some() && outliers.has_fences() {
+            self.ids.push(SaleId::new(&entry.id));
+        }
+        if listed_prices.is_some_and(|listed_prices| listed_prices.contains(&entry.price)) {
+            self.properties.push(Property {
+                address: entry.address(),
+                price: entry.price,
+                real_price,
+            });
+        }
+    }
+
+    fn merge(&mut self, other: Accumulator) {
+        self.prices.extend(other.prices);
+        self.real_prices.extend(other.real_prices);
+        self.ids.extend(other.ids);
+        for (rule, count) in other.excluded {
+            *self.excluded.entry(rule).or_default() += count;
+        }
+        self.excluded_ids.extend(other.excluded_ids);
+        self.properties.extend(other.properties);
+    }
+
+    /// Sorts the prices, keeping the real prices and ids of the sales in the same order.
+    fn sort(&mut self) {
+        if self.real_prices.is_empty() && self.ids.is_empty() {
+            self.prices.sort_unstable();
+            return;
+        }
+        let mut order: Vec<usize> = (0..self.prices.len()).collect();
+        order.sort_by_key(|&index| (self.prices[index], self.real_prices.get(index)));
+        self.prices = permute(std::mem::take(&mut self.prices), &order);
+        self.real_prices = permute(std::mem::take(&mut self.real_prices), &order);
+        self.ids = permute(std::mem::take(&mut self.ids), &order);
+    }
+
+    fn into_price_bucket(mut self, statistics: &Statistics) -> PriceBucket {
+        self.sort();
+        let mut excluded_ids = self.excluded_ids;
+        let mut bucket = statistics.price_bucket(&self.prices, &self.real_prices, self.properties);
+
+        // The prices left out by the fences are the ones outside the range of the bucket.
+        if bucket.outliers.is_some() {
+            excluded_ids.extend(
+                self.prices
+                    .into_iter()
+                    .zip(self.ids)
+                    .filter(|(price, _)| !bucket.range.contains(price))
+                    .map(|(_, id)| id.to_string()),
+            );
+            bucket
+                .properties
+                .retain(|property| bucket.range.contains(&property.price));
+        }
+        for (rule, count) in self.excluded {
+            bucket.add_outliers(rule, count);
+        }
+        if let Some(outliers) = &mut bucket.outliers {
+            excluded_ids.sort_unstable();
+            outliers.ids = excluded_ids;
+        }
+        bucket
+    }
+}
 
-    let mut prices: Vec<i32> = properties.iter().map(|p| p.price).collect();
-    prices.sort_unstable();
-    result.count = prices.len();
-    result.median = find_median(&prices);
-    let min = *prices.iter().min().unwrap_or(&0);
-    let max = *prices.iter().max().unwrap_or(&0);
-    result.range = min..max;
-    result.properties = properties
+/// The values in the given order of their indices, if there are any.
+fn permute<T>(values: Vec<T>, order: &[usize]) -> Vec<T> {
+    if values.is_empty() {
+        return values;
+    }
+    let mut values: Vec<Option<T>> = values.into_iter().map(Some).collect();
+    order
         .iter()
-        .filter(|p| p.price >= 300_000 && p.price <= 800_000)
-        .cloned()
-        .collect();
+        .filter_map(|&index| values.get_mut(index).and_then(Option::take))
+        .collect()
+}
+
+// Sorted maps are used throughout, so that the output doesn't depend on the order
+// the entries were aggregated in (or the number of threads used).
+type Buckets<T> = BTreeMap<PropertyType, BTreeMap<PropertyAge, T>>;
+type RegionBuckets<T> = BTreeMap<String, Buckets<T>>;
 
-    result
+fn map_buckets<T, U>(buckets: Buckets<T>, f: impl Fn(T) -> U) -> Buckets<U> {
+    buckets
+        .into_iter()
+        .map(|(property_type, age_buckets)| {
+            let age_buckets = age_buckets
+                .into_iter()
+                .map(|(property_age, value)| (property_age, f(value)))
+                .collect();
+            (property_type, age_buckets)
+        })
+        .collect()
 }
 
-fn find_median(prices: &Vec<i32>) -> f32 {
-    let len = prices.len();
-    if len >= 2 && len % 2 == 0 {
-        let middle = len / 2;
-        (prices[middle - 1] + prices[middle]) as f32 / 2f32
-    } else {
-        prices[len / 2] as f32
+/// Buckets of a postcode area, district or sector, and of the postcode regions within it.
+#[derive(Debug)]
+struct PostcodeNode<T> {
+    buckets: Buckets<T>,
+    children: BTreeMap<String, PostcodeNode<T>>,
+}
+
+impl<T> Default for PostcodeNode<T> {
+    fn default() -> Self {
+        PostcodeNode {
+            buckets: BTreeMap::new(),
+            children: BTreeMap::new(),
+        }
     }
 }
 
-fn process_year_entry(entry: &mut YearEntry) -> ProcessedYearEntry {
-    let mut result = ProcessedYearEntry {
-        year: entry.year,
-        buckets: HashMap::new(),
-    };
+impl<T> PostcodeNode<T> {
+    fn map<U>(self, f: &impl Fn(T) -> U) -> PostcodeNode<U> {
+        PostcodeNode {
+            buckets: map_buckets(self.buckets, f),
+            children: self
+                .children
+                .into_iter()
+                .map(|(code, child)| (code, child.map(f)))
+                .collect(),
+        }
+    }
+}
+
+fn add_to_buckets(
+    buckets: &mut Buckets<Accumulator>,
+    entry: &Entry,
+    real_price: Option<i32>,
+    outliers: &OutlierRules,
+    listed_prices: Option<&RangeInclusive<i32>>,
+) {
+    buckets
+        .entry(entry.property_type)
+        .or_default()
+        .entry(entry.property_age)
+        .or_default()
+        .add(entry, real_price, outliers, listed_prices);
+}
+
+fn merge_buckets(buckets: &mut Buckets<Accumulator>, other: Buckets<Accumulator>) {
+    for (property_type, age_buckets) in other {
+        let self_age_buckets = buckets.entry(property_type).or_default();
+        for (property_age, accumulator) in age_buckets {
+            self_age_buckets
+                .entry(property_age)
+                .or_default()
+                .merge(accumulator);
+        }
+    }
+}
+
+impl PostcodeNode<Accumulator> {
+    fn merge(&mut self, other: PostcodeNode<Accumulator>) {
+        merge_buckets(&mut self.buckets, other.buckets);
+        for (code, child) in other.children {
+            self.children.entry(code).or_default().merge(child);
+        }
+    }
+}
+
+/// Aggregates entries into per period buckets of every postcode area, district and sector,
+/// and of the selected regions, as they are read, so that the entries themselves
+/// don't have to be kept in memory or sorted.
+#[derive(Debug, Default)]
+struct Aggregator {
+    periods: BTreeMap<NaiveDate, PostcodeNode<Accumulator>>, // by the first day of the period
+    regions: BTreeMap<NaiveDate, RegionBuckets<Accumulator>>,
+    sales: SalesHistory,
+}
+
+impl Aggregator {
+    fn add(&mut self, entry: &Entry, grouping: &Grouping) {
+        // Sales without a postcode can't be attributed to any postcode.
+        let Some(postcode) = &entry.postcode else {
+            return;
+        };
+        if grouping.repeat_sales {
+            self.sales.add(entry);
+        }
+        if !grouping.stats {
+            return;
+        }
+        let start = grouping.period.start(entry.date, grouping.origin);
+        let real_price = grouping
+            .deflator
+            .as_ref()
+            .and_then(|deflator| deflator.real_price(entry.date, entry.price));
+        for region in grouping
+            .regions
+            .iter()
+            .filter(|region| region.contains(postcode))
+        {
+            let period_regions = self.regions.entry(start).or_default();
+            add_to_buckets(
+                period_regions.entry(region.name.clone()).or_default(),
+                entry,
+                real_price,
+                &grouping.statistics.outliers,
+                None,
+            );
+        }
+
+        let mut node = self.periods.entry(start).or_default();
+        let levels = [postcode.area(), postcode.district(), postcode.sector()];
+        for (level, code) in levels.iter().enumerate() {
+            node = node.children.entry(code.to_string()).or_default();
+            // Individual sales are only listed at the most detailed level.
+            let listed_prices = (level == levels.len() - 1).then_some(&grouping.listed_prices);
+            add_to_buckets(
+                &mut node.buckets,
+                entry,
+                real_price,
+                &grouping.statistics.outliers,
+                listed_prices,
+            );
+        }
+    }
 
-    for (property_type, age_entries) in entry.properties.iter_mut() {
-        for (property_age, properties) in age_entries.iter_mut() {
-            result
-                .buckets
-                .entry(*property_type)
-                .or_insert(HashMap::new())
-                .entry(*property_age)
-                .or_insert(to_price_bucket(properties));
+    /// Merges the buckets of another aggregator into this one.
+    /// The other aggregator is expected to have seen entries that come later in the file.
+    fn merge(&mut self, other: Aggregator) {
+        for (start, node) in other.periods {
+            self.periods.entry(start).or_default().merge(node);
         }
+        for (start, period_regions) in other.regions {
+            let self_period_regions = self.regions.entry(start).or_default();
+            for (name, buckets) in period_regions {
+                merge_buckets(self_period_regions.entry(name).or_default(), buckets);
+            }
+        }
+        self.sales.merge(other.sales);
     }
 
-    result
+    fn write_stats(mut self, path: &str, grouping: &Grouping) -> Result<(), Error> {
+        let mut out_file = File::create(path).map_err(Error::io(path))?;
+        out_file
+            .write_all("[".as_bytes())
+            .map_err(Error::io(path))?;
+        let mut index = 0;
+        let mut changes = ChangeTracker::new(
+            grouping.period,
+            grouping.origin,
+            grouping.change_min_sales,
+            grouping.statistics.bootstrap.clone(),
+        );
+        let mut write_period =
+            |start: NaiveDate,
+             end: NaiveDate,
+             mut node: PostcodeNode<PriceBucket>,
+             mut regions: RegionBuckets<PriceBucket>| {
+                println!("Saving stats for period: {} to {}", start, end);
+                // The changes are of the buckets' own sales, not of the stats they fall back to.
+                changes.add_changes(start, &mut node, &mut regions);
+                if let Some(min_sales) = &grouping.min_sales {
+                    min_sales.apply(&mut node, &mut regions);
+                }
+                if index > 0 {
+                    out_file
+                        .write_all(",".as_bytes())
+                        .map_err(Error::io(path))?;
+                }
+                index += 1;
+                let period_entries = ProcessedPeriodEntries {
+                    start,
+                    end,
+                    areas: node
+                        .children
+                        .into_iter()
+                        .map(|(area, node)| (area, AreaStats::from(node)))
+                        .collect(),
+                    regions: regions
+                        .into_iter()
+                        .map(|(name, buckets)| (name, RegionStats { buckets }))
+                        .collect(),
+                };
+                serde_json::to_writer(&out_file, &period_entries).map_err(Error::json(path))
+            };
+
+        match grouping.rolling {
+            Some(length) => rolling::for_each_window(
+                self.periods,
+                self.regions,
+                grouping.period,
+                length as usize,
+                &grouping.statistics,
+                write_period,
+            )?,
+            None => {
+                for (start, node) in self.periods {
+                    let regions = self.regions.remove(&start).unwrap_or_default();
+                    let to_price_bucket = |accumulator: Accumulator| {
+                        accumulator.into_price_bucket(&grouping.statistics)
+                    };
+                    write_period(
+                        start,
+                        grouping.period.end(start),
+                        node.map(&to_price_bucket),
+                        regions
+                            .into_iter()
+                            .map(|(name, buckets)| (name, map_buckets(buckets, to_price_bucket)))
+                            .collect(),
+                    )?;
+                }
+            }
+        }
+        out_file
+            .write_all("]".as_bytes())
+            .map_err(Error::io(path))?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Serialize, Clone)]
+struct Property {
+    address: String,
+    price: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    real_price: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+struct ProcessedPeriodEntries {
+    start: NaiveDate,
+    end: NaiveDate, // inclusive
+    areas: BTreeMap<String, AreaStats>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    regions: BTreeMap<String, RegionStats>,
+}
+
+#[derive(Debug, Serialize)]
+struct AreaStats {
+    buckets: Buckets<PriceBucket>,
+    districts: BTreeMap<String, DistrictStats>,
 }
 
 #[derive(Debug, Serialize)]
-struct ProcessedYearEntries {
-    year: i32,
-    postcodes: HashMap<String, Vec<ProcessedYearEntry>>,
+struct DistrictStats {
+    buckets: Buckets<PriceBucket>,
+    sectors: BTreeMap<String, SectorStats>,
 }
 
 #[derive(Debug, Serialize)]
-struct ProcessedYearEntry {
-    year: i32, // duplicate the year in this struct to make it easier to read the resulting JSON
-    buckets: HashMap<PropertyType, HashMap<PropertyAge, PriceBucket>>,
+struct SectorStats {
+    buckets: Buckets<PriceBucket>,
+}
+
+#[derive(Debug, Serialize)]
+struct RegionStats {
+    buckets: Buckets<PriceBucket>,
+}
+
+impl From<PostcodeNode<PriceBucket>> for AreaStats {
+    fn from(node: PostcodeNode<PriceBucket>) -> Self {
+        AreaStats {
+            buckets: node.buckets,
+            districts: node
+                .children
+                .into_iter()
+                .map(|(district, node)| (district, DistrictStats::from(node)))
+                .collect(),
+        }
+    }
+}
+
+impl From<PostcodeNode<PriceBucket>> for DistrictStats {
+    fn from(node: PostcodeNode<PriceBucket>) -> Self {
+        DistrictStats {
+            buckets: node.buckets,
+            sectors: node
+                .children
+                .into_iter()
+                .map(|(sector, node)| (sector, SectorStats::from(node)))
+                .collect(),
+        }
+    }
+}
+
+impl From<PostcodeNode<PriceBucket>> for SectorStats {
+    fn from(node: PostcodeNode<PriceBucket>) -> Self {
+        SectorStats {
+            buckets: node.buckets,
+        }
+    }
 }
 
 fn main() {
-    let args = Args::parse();
-    process_price_paid_data(args.file).unwrap_or_else(|_| {
-        println!("Processing price data failed");
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    // The default start date is meant for the stats of recent sales, the history looks further back.
+    if matches!(args.command, Some(Command::History { .. }))
+        && matches.value_source("from") == Some(ValueSource::DefaultValue)
+    {
+        args.from = None;
+    }
+    if let Some(name) = args.profile.clone() {
+        let result = profile::load(&args.config, &name)
+            .and_then(|profile| args.apply_profile(profile, &matches));
+        result.unwrap_or_else(|error| {
+            println!("Loading profile {} failed: {}", name, error);
+            process::exit(1);
+        });
+    }
+    let result = match &args.command {
+        Some(Command::Ingest) => ingest(&args),
+        Some(Command::History { output }) => process_history(&args, output),
+        None => process_price_paid_data(&args),
+    };
+    result.unwrap_or_else(|error| {
+        println!("Processing price data failed: {}", error);
+        process::exit(1);
     });
 }
 
-fn process_price_paid_data(path: String) -> Result<(), Box<dyn Error>> {
-    println!("Parsing CSV file...");
+fn process_price_paid_data(args: &Args) -> Result<(), Error> {
+    let grouping = args.grouping(true)?;
+    let mut aggregator = read_price_paid_data(args, &grouping)?;
 
-    let mut reader = csv::Reader::from_path(path)?;
-    let mut entries: Vec<Entry> = Vec::new();
+    let sales = std::mem::take(&mut aggregator.sales);
+    aggregator.write_stats(&args.output, &grouping)?;
+    if let Some(index_path) = &args.index {
+        let series = index::repeat_sales_index(sales, &grouping);
+        println!(
+            "Saving repeat-sales index of {} series to {}",
+            series.len(),
+            index_path
+        );
+        index::write_index(&series, index_path)?;
+    }
 
-    for result in reader.records() {
-        let record = result?;
+    Ok(())
+}
 
-        let date = NaiveDate::parse_from_str(record.get(2).unwrap(), DATE_FORMAT)?;
-        if date.year() < 2021 {
-            continue;
+fn process_history(args: &Args, output: &str) -> Result<(), Error> {
+    let grouping = args.grouping(false)?;
+    let aggregator = read_price_paid_data(args, &grouping)?;
+
+    let history = history::history(aggregator.sales);
+    println!(
+        "Saving the history of properties sold more than once to {}",
+        output
+    );
+    history::write_history(&history, output)
+}
+
+/// Reads the input files, applying the change file, and aggregates the entries that pass the filters.
+fn read_price_paid_data(args: &Args, grouping: &Grouping) -> Result<Aggregator, Error> {
+    let files = expand_paths(&args.files)?;
+    let filter = args.filter(&grouping.regions);
+    let threads = args
+        .threads
+        .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1);
+    let mut quarantined = Vec::new();
+
+    let updates = match &args.update {
+        Some(update_path) => {
+            println!("Reading change file...");
+            let (updates, summary) = read_updates(update_path, args.has_header, args.lenient)?;
+            quarantined.extend(summary.quarantined);
+            updates
         }
+        None => HashMap::new(),
+    };
 
-        let duration = to_duration_of_transfer(record.get(6).unwrap());
-        if duration != DurationOfTransfer::Leasehold {
-            continue;
+    let mut aggregator = Aggregator::default();
+    let mut applied_updates: HashSet<String> = HashSet::new();
+    let mut seen: HashMap<String, SeenRecord> = HashMap::new();
+    let mut conflicts = Vec::new();
+    let (mut records, mut duplicates) = (0, 0);
+    // Records left out of the caches were quarantined when the files were ingested.
+    let (mut parsed, mut cached_quarantined) = (args.update.is_some(), 0);
+    for (index, path) in files.iter().enumerate() {
+        let context = ProcessContext {
+            filter: &filter,
+            grouping,
+            updates: &updates,
+            seen: &seen,
+            // There is no need to remember the records of the last file, nothing is read after it.
+            track: index + 1 < files.len(),
+        };
+        let cache = if args.no_cache || path == input::STDIN {
+            None
+        } else {
+            cache::Cache::open(path, args.lenient)?
+        };
+        match &cache {
+            Some(cache) => cached_quarantined += cache.quarantined(),
+            None => parsed = true,
         }
+        let results = match cache {
+            Some(cache) => process_cache(path, &cache, threads, &context),
+            None => process_file(path, threads, args.has_header, args.lenient, &context)?,
+        };
 
-        let postcode_parts: Vec<&str> = record.get(3).unwrap().split(" ").collect();
-        let postcode1 = postcode_parts[0];
-        let postcode2 = postcode_parts.get(1).unwrap_or(&"");
-        if !INCLUDED_POSTCODES.contains(&postcode1) {
-            continue;
+        // Chunks are merged in file order, so the result is the same as a single-threaded run.
+        // Line numbers reported by the chunks are relative to the start of the chunk.
+        let mut line_offset = 0;
+        let mut file_seen = Vec::new();
+        for result in results {
+            let output = result.map_err(|error| error.with_line_offset(line_offset))?;
+            aggregator.merge(output.aggregator);
+            applied_updates.extend(output.applied_updates);
+            file_seen.extend(output.seen);
+            duplicates += output.duplicates;
+            conflicts.extend(output.conflicts);
+            records += output.summary.records;
+            quarantined.extend(output.summary.quarantined.into_iter().map(|mut record| {
+                record.line += line_offset;
+                record
+            }));
+            line_offset += output.summary.lines;
+        }
+        seen.extend(
+            file_seen
+                .into_iter()
+                .map(|(id, hash)| (id, SeenRecord { file: index, hash })),
+        );
+    }
+
+    // Whatever is left in the change file are the records that are not in the base file yet.
+    let mut new_entries: Vec<&Entry> = updates
+        .values()
+        .filter(|entry| !applied_updates.contains(&entry.id))
+        .filter(|entry| entry.record_status != RecordStatus::Deleted && filter.matches(entry))
+        .collect();
+    new_entries.sort_unstable_by(|a, b| (a.date, &a.id).cmp(&(b.date, &b.id)));
+    for entry in new_entries {
+        aggregator.add(entry, grouping);
+    }
+
+    println!("Read {} records", records);
+    if files.len() > 1 {
+        println!(
+            "Skipped {} records already read from another file, {} of them with conflicting data",
+            duplicates,
+            conflicts.len()
+        );
+        if !conflicts.is_empty() {
+            write_conflicts(&args.conflicts, &files, &conflicts)?;
+            println!("Conflicting records are listed in {}", args.conflicts);
+        }
+    }
+    // The quarantine file of the ingest step is only overwritten if anything was parsed since.
+    if args.lenient && parsed {
+        write_quarantine(&args.quarantine, &quarantined)?;
+        println!(
+            "Quarantined {} records that failed to parse (see {})",
+            quarantined.len(),
+            args.quarantine
+        );
+    }
+    if cached_quarantined > 0 {
+        println!(
+            "Left out {} records that failed to parse when the cached files were ingested",
+            cached_quarantined
+        );
+    }
+
+    Ok(aggregator)
+}
+
+/// Parses the input files and writes a cache of every one of them.
+fn ingest(args: &Args) -> Result<(), Error> {
+    let mut quarantined = Vec::new();
+    for path in expand_paths(&args.files)? {
+        if path == input::STDIN {
+            return Err(Error::Input {
+                path,
+                message: "the standard input can't be cached".to_string(),
+            });
         }
+        println!("Parsing {}...", path);
+        let mut input = input::open(&path)?;
+        let first_line = input::read_first_line(&mut input).map_err(Error::io(&path))?;
+        let has_header = match args.has_header {
+            Some(has_header) => has_header,
+            None => detect_header(&path, &first_line)?,
+        };
+        let reader = Cursor::new(first_line).chain(input);
+
+        let mut builder = cache::CacheBuilder::default();
+        let summary = read_entries(reader, &path, has_header, args.lenient, |entry| {
+            builder.add(&entry)
+        })?;
+
+        println!(
+            "Saving {} records to {}...",
+            builder.len(),
+            cache::path_for(&path)
+        );
+        builder.write(&path, summary.quarantined.len())?;
+        quarantined.extend(summary.quarantined);
+    }
+    if args.lenient {
+        write_quarantine(&args.quarantine, &quarantined)?;
+        println!(
+            "Quarantined {} records that failed to parse (see {})",
+            quarantined.len(),
+            args.quarantine
+        );
+    }
+
+    Ok(())
+}
 
-        let property_type = to_property_type(record.get(4).unwrap());
-        if property_type == PropertyType::Other {
+/// Expands the glob patterns among the given paths. Matches of a pattern are sorted by name.
+fn expand_paths(paths: &[String]) -> Result<Vec<String>, Error> {
+    let mut result = Vec::new();
+    for path in paths {
+        if !path.contains(['*', '?', '[']) {
+            result.push(path.clone());
             continue;
         }
+        let pattern_error = |message: String| Error::Input {
+            path: path.clone(),
+            message,
+        };
+        let mut matches = glob::glob(path)
+            .map_err(|error| pattern_error(error.to_string()))?
+            .map(|entry| entry.map(|path| path.to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| pattern_error(error.to_string()))?;
+        if matches.is_empty() {
+            return Err(pattern_error("no files match the pattern".to_string()));
+        }
+        matches.sort();
+        result.extend(matches);
+    }
+
+    Ok(result)
+}
+
+/// Reads a single input file, in parallel if possible.
+/// Returns the results of every part of the file in file order.
+fn process_file(
+    path: &str,
+    threads: usize,
+    has_header: Option<bool>,
+    lenient: bool,
+    context: &ProcessContext,
+) -> Result<Vec<ProcessResult>, Error> {
+    let mut input = input::open(path)?;
+    let first_line = input::read_first_line(&mut input).map_err(Error::io(path))?;
+    let has_header = match has_header {
+        Some(has_header) => has_header,
+        None => detect_header(path, &first_line)?,
+    };
+    if has_header {
+        println!("Skipping the header row of {}", path);
+    }
+
+    // Compressed files and the standard input can only be read from start to end.
+    if threads > 1 && input::is_splittable(path)? {
+        drop(input);
+        println!(
+            "Parsing {} and calculating stats per postcode per period ({} threads)...",
+            path, threads
+        );
+        process_chunks(path, threads, has_header, lenient, context)
+    } else {
+        println!(
+            "Parsing {} and calculating stats per postcode per period...",
+            path
+        );
+        let reader = Cursor::new(first_line).chain(input);
+        Ok(vec![process_reader(
+            reader, path, has_header, lenient, context,
+        )])
+    }
+}
+
+/// The official Price Paid files have no header row, but files saved by other tools may have one.
+/// The first row is taken to be a header if its price and date columns don't parse.
+fn detect_header(path: &str, first_line: &[u8]) -> Result<bool, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(first_line);
+    let mut record = csv::StringRecord::new();
+    if !reader.read_record(&mut record).map_err(Error::csv(path))? {
+        return Ok(false);
+    }
+    check_column_count(record.len()).map_err(|error| Error::Record {
+        path: path.to_string(),
+        line: 1,
+        error,
+    })?;
+    let is_data = record
+        .get(1)
+        .is_some_and(|price| price.parse::<i32>().is_ok())
+        && record
+            .get(2)
+            .is_some_and(|date| NaiveDate::parse_from_str(date, DATE_FORMAT).is_ok());
+
+    Ok(!is_data)
+}
+
+fn check_column_count(count: usize) -> Result<(), RecordError> {
+    if count == COLUMN_COUNT {
+        return Ok(());
+    }
+    Err(RecordError {
+        column: None,
+        value: String::new(),
+        message: format!(
+            "expected {} columns, found {} (is this a Price Paid Data file?)",
+            COLUMN_COUNT, count
+        ),
+    })
+}
+
+/// Splits the file into at most `count` byte ranges of roughly equal size.
+/// Every range starts at the beginning of a line, which is also the beginning of a record,
+/// as the Price Paid Data has no line breaks inside quoted fields.
+fn split_into_chunks(path: &str, count: usize) -> Result<Vec<Range<u64>>, Error> {
+    let mut reader = BufReader::new(File::open(path).map_err(Error::io(path))?);
+    let len = reader.get_ref().metadata().map_err(Error::io(path))?.len();
+    // Every chunk is at least a byte long, so that no chunk after the first starts at 0.
+    let count = (count as u64).clamp(1, len.max(1));
+    let chunk_len = len / count;
+
+    let mut starts = vec![0];
+    let mut line = Vec::new();
+    for index in 1..count {
+        let offset = (index * chunk_len).max(*starts.last().unwrap());
+        if offset >= len {
+            break;
+        }
+        // Skip to the start of the next line. If the offset happens to be at the start
+        // of a line already, the preceding newline makes sure the line is not skipped.
+        reader
+            .seek(SeekFrom::Start(offset - 1))
+            .map_err(Error::io(path))?;
+        line.clear();
+        let skipped = reader
+            .read_until(b'\n', &mut line)
+            .map_err(Error::io(path))? as u64;
+        let start = offset - 1 + skipped;
+        if start >= len {
+            break;
+        }
+        if start > *starts.last().unwrap() {
+            starts.push(start);
+        }
+    }
+
+    let ends = starts.iter().skip(1).copied().chain([len]);
+    Ok(starts
+        .iter()
+        .copied()
+        .zip(ends)
+        .map(|(start, end)| start..end)
+        .collect())
+}
+
+/// A record that failed to parse in lenient mode.
+#[derive(Debug)]
+struct QuarantinedRecord {
+    path: String,
+    line: u64,
+    record: csv::ByteRecord,
+    error: RecordError,
+}
 
-        let price: i32 = record.get(1).unwrap().parse().unwrap();
-        let property_age = to_property_age(record.get(5).unwrap());
-        let paon = record.get(7).unwrap();
-        let saon = record.get(8).unwrap();
-        let street = record.get(9).unwrap();
-        let city = record.get(11).unwrap();
-        let mut address = "".to_string();
-        if !paon.is_empty() {
-            address += paon;
-            address += ", ";
-        }
-        if !saon.is_empty() {
-            address += saon;
-            address += ", ";
-        }
-        address += street;
-        address += ", ";
-        address += city;
-        address += ", ";
-        address += postcode1;
-        if !postcode2.is_empty() {
-            address += " ";
-            address += postcode2;
-        }
-
-        let entry = Entry {
-            price,
-            date,
-            address,
-            postcode: postcode1.to_string(),
-            property_type,
-            property_age,
-            // duration,
+#[derive(Debug, Default)]
+struct ReadSummary {
+    records: u64,
+    lines: u64,
+    quarantined: Vec<QuarantinedRecord>,
+}
+
+/// Reads the entries from CSV data, passing each one to `f`.
+/// In lenient mode the records that fail to parse are quarantined instead of failing the read.
+fn read_entries<R: Read>(
+    reader: R,
+    path: &str,
+    has_header: bool,
+    lenient: bool,
+    mut f: impl FnMut(Entry),
+) -> Result<ReadSummary, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(has_header)
+        .flexible(true) // the column count is checked when converting to an entry
+        .from_reader(reader);
+
+    let mut summary = ReadSummary::default();
+    let mut record = csv::ByteRecord::new();
+    while reader
+        .read_byte_record(&mut record)
+        .map_err(Error::csv(path))?
+    {
+        let line = record.position().map_or(0, |position| position.line());
+        let result = match csv::StringRecord::from_byte_record(record.clone()) {
+            Ok(record) => Entry::from_record(&record),
+            Err(error) => {
+                let column = error.utf8_error().field();
+                let value = String::from_utf8_lossy(&record[column]);
+                Err(RecordError::column(column, &value, "invalid UTF-8"))
+            }
         };
-        entries.push(entry);
-    }
-
-    println!("Sorting and filtering entries...");
-
-    entries.sort_unstable_by(|entry1, entry2| entry1.date.cmp(&entry2.date));
-    // It's less pretty but faster to filter in the reader loop above than here.
-    // Given the huge size of our CSV, any performance improvement is welcome.
-    // entries = entries
-    //     .into_iter()
-    //     .filter(|entry| entry.date.year() >= 2021)
-    //     .filter(|entry| entry.duration == DurationOfTransfer::Freehold)
-    //     .filter(|entry| INCLUDED_POSTCODES.contains(&entry.postcode.as_str()))
-    //     .collect();
-
-    println!("Calculating stats per postcode per year...");
-
-    let mut year: i32 = entries[0].date.year();
-    let mut postcode_year_entries: HashMap<String, YearEntry> = HashMap::new();
-
-    let mut out_file = File::create("stats.json")?;
-    out_file.write("[".as_bytes())?;
-    let mut it = entries.iter().peekable();
-    while let Some(entry) = it.next() {
-        if entry.date.year() != year || it.peek().is_none() {
-            let mut processed_year_entries: HashMap<String, Vec<ProcessedYearEntry>> =
-                HashMap::new();
-            for (postcode, year_entry) in postcode_year_entries.iter_mut() {
-                let processed_year_entry = process_year_entry(year_entry);
-                let postcode_processed_year_entries = processed_year_entries
-                    .entry(postcode.clone())
-                    .or_insert(vec![]);
-                postcode_processed_year_entries.push(processed_year_entry);
+        match result {
+            Ok(entry) => {
+                summary.records += 1;
+                f(entry);
             }
-            println!("Saving stats for year: {:?}", year);
-            serde_json::to_writer(
-                &out_file,
-                &ProcessedYearEntries {
-                    year,
-                    postcodes: processed_year_entries,
-                },
-            )?;
-            out_file.write(",".as_bytes())?;
-
-            year = entry.date.year();
-            postcode_year_entries.clear();
-        }
-
-        let properties = postcode_year_entries
-            .entry(entry.postcode.clone())
-            .or_insert(YearEntry {
-                properties: HashMap::new(),
-                year: entry.date.year(),
+            Err(error) if lenient => summary.quarantined.push(QuarantinedRecord {
+                path: path.to_string(),
+                line,
+                record: record.clone(),
+                error,
+            }),
+            Err(error) => {
+                return Err(Error::Record {
+                    path: path.to_string(),
+                    line,
+                    error,
+                })
+            }
+        }
+    }
+    summary.lines = reader.position().line() - 1;
+
+    Ok(summary)
+}
+
+/// A record that was read from one of the previous files.
+#[derive(Debug)]
+struct SeenRecord {
+    file: usize,
+    hash: u64, // tells apart the records that have the same id but different data
+}
+
+/// A record that has the same id as a record from one of the previous files, but different data.
+#[derive(Debug)]
+struct Conflict {
+    id: String,
+    kept_file: usize,
+    skipped_file: String,
+}
+
+/// Settings and records from elsewhere that decide what happens to the records being read.
+struct ProcessContext<'a> {
+    filter: &'a Filter,
+    grouping: &'a Grouping,
+    updates: &'a HashMap<String, Entry>, // change file records, applied on top of the records read
+    seen: &'a HashMap<String, SeenRecord>, // records from the previous files, which take precedence
+    track: bool, // whether to return the ids of the records read, for the files that follow
+}
+
+#[derive(Debug, Default)]
+struct ProcessOutput {
+    aggregator: Aggregator,
+    applied_updates: Vec<String>,
+    seen: Vec<(String, u64)>,
+    duplicates: u64,
+    conflicts: Vec<Conflict>,
+    summary: ReadSummary,
+}
+
+type ProcessResult = Result<ProcessOutput, Error>;
+
+/// Splits the file into byte ranges that are parsed and aggregated on separate threads.
+/// Returns the results in file order.
+fn process_chunks(
+    path: &str,
+    threads: usize,
+    has_header: bool,
+    lenient: bool,
+    context: &ProcessContext,
+) -> Result<Vec<ProcessResult>, Error> {
+    let chunks = split_into_chunks(path, threads)?;
+    Ok(thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let has_header = has_header && index == 0;
+                scope.spawn(move || -> ProcessResult {
+                    let mut file = File::open(path).map_err(Error::io(path))?;
+                    file.seek(SeekFrom::Start(chunk.start))
+                        .map_err(Error::io(path))?;
+                    let reader = file.take(chunk.end - chunk.start);
+                    process_reader(reader, path, has_header, lenient, context)
+                })
             })
-            .properties
-            .entry(entry.property_type)
-            .or_insert(HashMap::new())
-            .entry(entry.property_age)
-            .or_insert(vec![]);
-
-        properties.push(Property {
-            address: entry.address.clone(),
-            price: entry.price,
-        });
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("CSV parsing thread panicked"))
+            .collect()
+    }))
+}
+
+/// Parses and aggregates the records read from the reader.
+fn process_reader<R: Read>(
+    reader: R,
+    path: &str,
+    has_header: bool,
+    lenient: bool,
+    context: &ProcessContext,
+) -> ProcessResult {
+    let mut output = ProcessOutput::default();
+    output.summary = read_entries(reader, path, has_header, lenient, |entry| {
+        process_entry(&mut output, entry, path, context)
+    })?;
+
+    Ok(output)
+}
+
+/// Aggregates the records of a cached file in parallel.
+/// Returns the results of every part of the file in file order.
+fn process_cache(
+    path: &str,
+    cache: &cache::Cache,
+    threads: usize,
+    context: &ProcessContext,
+) -> Vec<ProcessResult> {
+    println!(
+        "Reading {} records of {} from the cache and calculating stats per postcode per period...",
+        cache.len(),
+        path
+    );
+    thread::scope(|scope| {
+        let handles: Vec<_> = cache
+            .split(threads)
+            .into_iter()
+            .map(|rows| {
+                scope.spawn(move || -> ProcessResult {
+                    let mut output = ProcessOutput::default();
+                    output.summary.records = rows.len() as u64;
+                    for row in rows {
+                        process_entry(&mut output, cache.entry(row)?, path, context);
+                    }
+                    Ok(output)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("cache reading thread panicked"))
+            .collect()
+    })
+}
+
+/// Records already read from a previous file are skipped, the change file records
+/// are applied to the rest, which are then aggregated if they pass the filters.
+fn process_entry(
+    output: &mut ProcessOutput,
+    mut entry: Entry,
+    path: &str,
+    context: &ProcessContext,
+) {
+    let hash = entry_hash(&entry);
+    if let Some(seen) = context.seen.get(&entry.id) {
+        output.duplicates += 1;
+        if seen.hash != hash {
+            output.conflicts.push(Conflict {
+                id: entry.id,
+                kept_file: seen.file,
+                skipped_file: path.to_string(),
+            });
+        }
+        return;
+    }
+    if context.track {
+        output.seen.push((entry.id.clone(), hash));
+    }
+
+    if let Some(update) = context.updates.get(&entry.id) {
+        output.applied_updates.push(entry.id.clone());
+        if update.record_status == RecordStatus::Deleted {
+            return;
+        }
+        entry = update.clone();
+    }
+    if context.filter.matches(&entry) {
+        output.aggregator.add(&entry, context.grouping);
+    }
+}
+
+fn entry_hash(entry: &Entry) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    entry.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Reads a monthly change file, keyed by transaction id.
+/// When applied on top of the base file, "A" records add a new entry,
+/// "C" records replace the existing one and "D" records remove it.
+fn read_updates(
+    update_path: &str,
+    has_header: Option<bool>,
+    lenient: bool,
+) -> Result<(HashMap<String, Entry>, ReadSummary), Error> {
+    let mut updates = HashMap::new();
+    let (mut added, mut changed, mut deleted) = (0, 0, 0);
+
+    let mut input = input::open(update_path)?;
+    let first_line = input::read_first_line(&mut input).map_err(Error::io(update_path))?;
+    let has_header = match has_header {
+        Some(has_header) => has_header,
+        None => detect_header(update_path, &first_line)?,
+    };
+    let reader = Cursor::new(first_line).chain(input);
+    let summary = read_entries(reader, update_path, has_header, lenient, |entry| {
+        match entry.record_status {
+            RecordStatus::Added => added += 1,
+            RecordStatus::Changed => changed += 1,
+            RecordStatus::Deleted => deleted += 1,
+        }
+        updates.insert(entry.id.clone(), entry);
+    })?;
+    println!(
+        "Change file has {} added, {} changed and {} deleted records",
+        added, changed, deleted
+    );
+
+    Ok((updates, summary))
+}
+
+fn write_conflicts(path: &str, files: &[String], conflicts: &[Conflict]) -> Result<(), Error> {
+    let mut writer = csv::Writer::from_path(path).map_err(Error::csv(path))?;
+    writer
+        .write_record(["transaction id", "kept from", "skipped from"])
+        .map_err(Error::csv(path))?;
+    for conflict in conflicts {
+        writer
+            .write_record([
+                &conflict.id,
+                &files[conflict.kept_file],
+                &conflict.skipped_file,
+            ])
+            .map_err(Error::csv(path))?;
     }
-    serde_json::to_writer(&out_file, &postcode_year_entries)?;
-    out_file.write("]".as_bytes())?;
+    writer.flush().map_err(Error::io(path))?;
+
+    Ok(())
+}
+
+/// Writes the quarantined records as they were in the source file, followed by
+/// the file name, line number and the reason the record was rejected.
+fn write_quarantine(path: &str, quarantined: &[QuarantinedRecord]) -> Result<(), Error> {
+    let mut writer = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_path(path)
+        .map_err(Error::csv(path))?;
+    for record in quarantined {
+        let mut row = record.record.clone();
+        row.push_field(record.path.as_bytes());
+        row.push_field(record.line.to_string().as_bytes());
+        row.push_field(record.error.to_string().as_bytes());
+        writer.write_byte_record(&row).map_err(Error::csv(path))?;
+    }
+    writer.flush().map_err(Error::io(path))?;
 
     Ok(())
 }
@@ -297,6 +1770,21 @@ fn to_property_age(str: &str) -> PropertyAge {
     }
 }
 
+fn to_ppd_category(str: &str) -> PpdCategory {
+    match str {
+        "B" => PpdCategory::Additional,
+        _ => PpdCategory::Standard,
+    }
+}
+
+fn to_record_status(str: &str) -> RecordStatus {
+    match str {
+        "C" => RecordStatus::Changed,
+        "D" => RecordStatus::Deleted,
+        _ => RecordStatus::Added,
+    }
+}
+
 fn to_duration_of_transfer(str: &str) -> DurationOfTransfer {
     match str {
         "F" => DurationOfTransfer::Freehold,
@@ -304,36 +1792,169 @@ fn to_duration_of_transfer(str: &str) -> DurationOfTransfer {
     }
 }
 
-// Greater London is too big and includes fairly remote areas.
-// const LONDON_POSTCODES: &'static [&'static str] = &[
-//     "EC1A", "EC1M", "EC1N", "EC1P", "EC1R", "EC1V", "EC1Y", "EC2A", "EC2M", "EC2N", "EC2P", "EC2R",
-//     "EC2V", "EC2Y", "EC3A", "EC3M", "EC3N", "EC3P", "EC3R", "EC3V", "EC4A", "EC4M", "EC4N", "EC4P",
-//     "EC4R", "EC4V", "EC4Y", "WC1A", "WC1B", "WC1E", "WC1H", "WC1N", "WC1R", "WC1V", "WC1X", "WC2A",
-//     "WC2B", "WC2E", "WC2H", "WC2N", "WC2R", "E1", "E2", "E3", "E4", "E5", "E6", "E7", "E8", "E9",
-//     "E10", "E11", "E12", "E13", "E14", "E15", "E16", "E17", "E18", "E19", "E20", "N1", "N2", "N3",
-//     "N4", "N5", "N6", "N7", "N8", "N9", "N10", "N11", "N12", "N13", "N14", "N15", "N16", "N17",
-//     "N18", "N19", "N20", "N21", "N22", "NW1", "NW2", "NW3", "NW4", "NW5", "NW6", "NW7", "NW8",
-//     "NW9", "NW10", "NW11", "SE1", "SE2", "SE3", "SE4", "SE5", "SE6", "SE7", "SE8", "SE9", "SE10",
-//     "SE11", "SE12", "SE13", "SE14", "SE15", "SE16", "SE17", "SE18", "SE19", "SE20", "SE21", "SE22",
-//     "SE23", "SE24", "SE25", "SE26", "SE27", "SE28", "SW1", "SW2", "SW3", "SW4", "SW5", "SW6",
-//     "SW7", "SW8", "SW9", "SW10", "SW11", "SW12", "SW13", "SW14", "SW15", "SW16", "SW17", "SW18",
-//     "SW19", "SW20", "W1", "W2", "W3", "W4", "W5", "W6", "W7", "W8", "W9", "W10", "W11", "W12",
-//     "W13", "W14",
-// ];
-
-// Inner London still includes relatively far away areas (like E4 and N4).
-// https://en.wikipedia.org/wiki/Inner_London
-
-// const CENTRAL_LONDON_POSTCODES: &'static [&'static str] = &[
-//     "EC1A", "EC1M", "EC1N", "EC1R", "EC1V", "EC1Y", "EC2A", "EC2M", "EC2N", "EC2R", "EC2V", "EC2Y",
-//     "EC3A", "EC3M", "EC3N", "EC3R", "EC3V", "EC4A", "EC4M", "EC4N", "EC4R", "EC4V", "EC4Y", "WC1A",
-//     "WC1B", "WC1E", "WC1H", "WC1N", "WC1R", "WC1V", "WC1X", "WC2A", "WC2B", "WC2E", "WC2H", "WC2N",
-//     "WC2R", "E1", "E2", "E3", "E8", "E9", "E14", "E15", "E16", "N1", "N5", "N8", "N16", "NW1",
-//     "NW3", "NW5", "NW6", "NW8", "NW10", "SE1", "SE3", "SE4", "SE5", "SE7", "SE8", "SE10", "SE11",
-//     "SE13", "SE14", "SE15", "SE16", "SE17", "SE18", "SW1", "SW2", "SW3", "SW4", "SW5", "SW6",
-//     "SW7", "SW8", "SW9", "SW10", "SW11", "W1", "W2", "W8", "W9", "W10", "W11", "W14",
-// ];
-
-const DESIRABLE_POSTCODES: &'static [&'static str] = &["E14", "E16", "SE1", "SE16"];
-
-const INCLUDED_POSTCODES: &'static [&'static str] = DESIRABLE_POSTCODES;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POSTCODES: [&str; 5] = ["SE16 4AB", "SE16 7CD", "SE1 7PB", "E14 9GE", "N1 9GU"];
+    const TYPES: [&str; 4] = ["D", "S", "T", "F"];
+
+    fn aggregate(path: &str, threads: usize, args: &Args) -> String {
+        let grouping = args.grouping(true).unwrap();
+        let filter = args.filter(&grouping.regions);
+        let (updates, seen) = (HashMap::new(), HashMap::new());
+        let context = ProcessContext {
+            filter: &filter,
+            grouping: &grouping,
+            updates: &updates,
+            seen: &seen,
+            track: false,
+        };
+        let results = process_chunks(path, threads, false, false, &context).unwrap();
+        assert!(results.len() <= threads);
+        let mut aggregator = Aggregator::default();
+        for result in results {
+            aggregator.merge(result.unwrap().aggregator);
+        }
+        // The sales of the properties are kept in a hash map, so only their order may differ.
+        let mut sales: Vec<String> = aggregator
+            .sales
+            .properties
+            .iter()
+            .map(|(key, sales)| format!("{:?}: {:?}", key, sales))
+            .collect();
+        sales.sort();
+        format!(
+            "{:?} {:?} {:?}",
+            aggregator.periods, aggregator.regions, sales
+        )
+    }
+
+    fn record(id: u32, price: i32, status: &str) -> String {
+        format!(
+            "\"{{{:08}}}\",\"{}\",\"2021-06-01 00:00\",\"SE16 4AB\",\"F\",\"N\",\"L\",\"{}\",\"\",\"HIGH ST\",\"\",\"LONDON\",\"SOUTHWARK\",\"GREATER LONDON\",\"A\",\"{}\"",
+            id, price, id, status
+        )
+    }
+
+    #[test]
+    fn change_file_records_are_applied() {
+        let dir = std::env::temp_dir();
+        let base = dir.join(format!("home-uk-base-{}.csv", std::process::id()));
+        let update = dir.join(format!("home-uk-update-{}.csv", std::process::id()));
+        let (base, update) = (base.to_str().unwrap(), update.to_str().unwrap());
+        let base_records = [
+            record(1, 100, "A"),
+            record(2, 200, "A"),
+            record(3, 300, "A"),
+            record(5, 500, "A"),
+        ];
+        std::fs::write(base, base_records.join("\n")).unwrap();
+        // Added again, changed, deleted and new.
+        let update_records = [
+            record(1, 110, "A"),
+            record(2, 220, "C"),
+            record(3, 300, "D"),
+            record(4, 440, "A"),
+        ];
+        std::fs::write(update, update_records.join("\n")).unwrap();
+
+        let args = Args::parse_from([
+            "home-uk",
+            "--no-cache",
+            "-f",
+            base,
+            "--update",
+            update,
+            "--postcodes",
+            "*",
+            "--from",
+            "2021-01-01",
+            "--tenure",
+        ]);
+        let grouping = args.grouping(true).unwrap();
+        let aggregator = read_price_paid_data(&args, &grouping).unwrap();
+        let mut prices: Vec<i32> = aggregator
+            .periods
+            .values()
+            .flat_map(|node| node.children.values())
+            .flat_map(|area| {
+                area.buckets
+                    .values()
+                    .flat_map(|age_buckets| age_buckets.values())
+            })
+            .flat_map(|accumulator| accumulator.prices.iter().copied())
+            .collect();
+        prices.sort_unstable();
+        assert_eq!(prices, [110, 220, 440, 500]);
+
+        std::fs::remove_file(base).unwrap();
+        std::fs::remove_file(update).unwrap();
+    }
+
+    #[test]
+    fn list_options_stop_at_subcommands() {
+        let args = Args::parse_from(["home-uk", "-f", "a.csv", "b.csv", "ingest"]);
+        assert!(matches!(args.command, Some(Command::Ingest)));
+        assert_eq!(args.files, ["a.csv", "b.csv"]);
+
+        let args = Args::parse_from(["home-uk", "--tenure", "history", "--type", "flat"]);
+        assert!(matches!(args.command, Some(Command::History { .. })));
+        assert!(args.tenure.is_empty());
+        assert_eq!(args.property_types, [PropertyType::Flat]);
+    }
+
+    #[test]
+    fn chunks_aggregate_like_a_single_thread() {
+        let lines: Vec<String> = (0..1000)
+            .map(|index| {
+                format!(
+                    "\"{{{:08}}}\",\"{}\",\"{}-{:02}-{:02} 00:00\",\"{}\",\"{}\",\"{}\",\"{}\",\"{}\",\"\",\"HIGH ST\",\"\",\"LONDON\",\"SOUTHWARK\",\"GREATER LONDON\",\"A\",\"A\"",
+                    index,
+                    100_000 + index * 7919 % 900_000,
+                    2015 + index % 10,
+                    index % 12 + 1,
+                    index % 28 + 1,
+                    POSTCODES[index % POSTCODES.len()],
+                    TYPES[index % TYPES.len()],
+                    ["Y", "N"][index % 2],
+                    ["F", "L"][index / 2 % 2],
+                    index % 100,
+                )
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!("home-uk-chunks-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, lines.join("\n")).unwrap();
+
+        let args = Args::parse_from([
+            "home-uk",
+            "--postcodes",
+            "*",
+            "--from",
+            "2015-01-01",
+            "--tenure",
+            "--period",
+            "quarter",
+            "--region",
+            "london",
+            "--index",
+            "index.json",
+        ]);
+        let single = aggregate(path, 1, &args);
+        for threads in [2, 3, 8] {
+            assert_eq!(
+                aggregate(path, threads, &args),
+                single,
+                "{} threads",
+                threads
+            );
+        }
+
+        // More threads than bytes in the file.
+        std::fs::write(path, &lines[0]).unwrap();
+        let single = aggregate(path, 1, &args);
+        assert_eq!(aggregate(path, lines[0].len() + 10, &args), single);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt, ops::Range};
+
+use crate::{
+    error::Error,
+    stats::{find_median, find_quantile},
+    Entry, PpdCategory,
+};
+
+/// Scales the median absolute deviation to the standard deviation of normally distributed prices.
+const MAD_SCALE: f64 = 0.6745;
+
+/// Why a sale was left out of the stats of its bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutlierRule {
+    Floor,     // priced below the floor
+    Ceiling,   // priced above the ceiling
+    CategoryB, // additional price paid, e.g. a repossession or a bulk transfer
+    Iqr,       // outside the interquartile range fences of the bucket
+    Mad,       // too many median absolute deviations away from the median of the bucket
+}
+
+/// Which sales are left out of the stats. The floor, ceiling and category rules are checked
+/// for every sale, the fences of the other rules are worked out from the prices of each bucket.
+#[derive(Debug, Clone, Default)]
+pub struct OutlierRules {
+    pub floor: Option<i32>,
+    pub ceiling: Option<i32>,
+    pub exclude_category_b: bool,
+    pub iqr_fence: Option<f64>, // multiple of the IQR below P25 and above P75, e.g. 1.5
+    pub mad_threshold: Option<f64>, // modified z-score, e.g. 3.5
+}
+
+impl OutlierRules {
+    pub fn new(
+        floor: Option<i32>,
+        ceiling: Option<i32>,
+        exclude_category_b: bool,
+        iqr_fence: Option<f64>,
+        mad_threshold: Option<f64>,
+    ) -> Result<OutlierRules, Error> {
+        if let (Some(floor), Some(ceiling)) = (floor, ceiling) {
+            if floor > ceiling {
+                return Err(Error::Config(format!(
+                    "outlier floor {} is above the ceiling {}",
+                    floor, ceiling
+                )));
+            }
+        }
+        for (name, value) in [("IQR fence", iqr_fence), ("MAD threshold", mad_threshold)] {
+            if let Some(value) = value.filter(|value| value.is_nan() || *value <= 0.0) {
+                return Err(Error::Config(format!("{} {} is not positive", name, value)));
+            }
+        }
+        Ok(OutlierRules {
+            floor,
+            ceiling,
+            exclude_category_b,
+            iqr_fence,
+            mad_threshold,
+        })
+    }
+
+    /// Whether the fences of the buckets are worked out from their prices.
+    pub fn has_fences(&self) -> bool {
+        self.iqr_fence.is_some() || self.mad_threshold.is_some()
+    }
+
+    /// The rule that leaves the sale out regardless of the other sales in its bucket, if any.
+    pub fn check(&self, entry: &Entry) -> Option<OutlierRule> {
+        if self.floor.is_some_and(|floor| entry.price < floor) {
+            Some(OutlierRule::Floor)
+        } else if self.ceiling.is_some_and(|ceiling| entry.price > ceiling) {
+            Some(OutlierRule::Ceiling)
+        } else if self.exclude_category_b && entry.ppd_category == PpdCategory::Additional {
+            Some(OutlierRule::CategoryB)
+        } else {
+            None
+        }
+    }
+
+    /// Indices of the sorted prices within the fences, with the number of prices
+    /// left out by each rule. The IQR fences are checked first.
+    pub fn fence(&self, prices: &[i32]) -> (Range<usize>, BTreeMap<OutlierRule, usize>) {
+        let mut kept = 0..prices.len();
+        let mut excluded = BTreeMap::new();
+        if prices.is_empty() {
+            return (kept, excluded);
+        }
+        let mut fences = Vec::new();
+        if let Some(fence) = self.iqr_fence {
+            let (p25, p75) = (find_quantile(prices, 25.0), find_quantile(prices, 75.0));
+            let iqr = (p75 - p25) as f64;
+            let bounds = (p25 as f64 - fence * iqr, p75 as f64 + fence * iqr);
+            fences.push((OutlierRule::Iqr, bounds));
+        }
+        if let Some(threshold) = self.mad_threshold {
+            let median = find_median(prices);
+            let mut deviations: Vec<f64> = prices
+                .iter()
+                .map(|&price| (price as f64 - median).abs())
+                .collect();
+            deviations.sort_unstable_by(f64::total_cmp);
+            let mad = deviations[deviations.len() / 2];
+            // Without any spread around the median there is nothing to measure the prices by.
+            if mad > 0.0 {
+                let distance = threshold * mad / MAD_SCALE;
+                fences.push((OutlierRule::Mad, (median - distance, median + distance)));
+            }
+        }
+
+        for (rule, (low, high)) in fences {
+            let start = kept.start + prices[kept.clone()].partition_point(|&p| (p as f64) < low);
+            let end = kept.start + prices[kept.clone()].partition_point(|&p| (p as f64) <= high);
+            let count = (start - kept.start) + (kept.end - end);
+            if count > 0 {
+                *excluded.entry(rule).or_default() += count;
+            }
+            kept = start..end;
+        }
+        (kept, excluded)
+    }
+}
+
+/// The transaction id of a sale that may end up outside the fences, kept compactly while the sales
+/// are aggregated. The ids of the Price Paid Data are GUIDs in braces, such as
+/// "{8F1B26BD-8F22-4CF1-E053-6B04A8C0A3B1}", which fit in 16 bytes, other ids are kept as they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaleId {
+    Guid([u8; 16]),
+    Other(Box<str>),
+}
+
+impl SaleId {
+    pub fn new(id: &str) -> SaleId {
+        parse_guid(id).map_or_else(|| SaleId::Other(id.into()), SaleId::Guid)
+    }
+}
+
+/// The bytes of an upper case GUID in braces, which is formatted back the same way.
+fn parse_guid(id: &str) -> Option<[u8; 16]> {
+    let digits = id.strip_prefix('{')?.strip_suffix('}')?;
+    let groups: Vec<&str> = digits.split('-').collect();
+    if groups.iter().map(|group| group.len()).ne([8, 4, 4, 4, 12])
+        || !digits
+            .bytes()
+            .all(|c| c == b'-' || c.is_ascii_digit() || (b'A'..=b'F').contains(&c))
+    {
+        return None;
+    }
+    let hex = groups.concat();
+    let mut bytes = [0; 16];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+impl fmt::Display for SaleId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaleId::Guid(bytes) => {
+                f.write_str("{")?;
+                for (index, byte) in bytes.iter().enumerate() {
+                    if [4, 6, 8, 10].contains(&index) {
+                        f.write_str("-")?;
+                    }
+                    write!(f, "{:02X}", byte)?;
+                }
+                f.write_str("}")
+            }
+            SaleId::Other(id) => f.write_str(id),
+        }
+    }
+}
+
+/// The sales of a bucket that were left out of its stats.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Outliers {
+    pub count: usize,
+    pub rules: BTreeMap<OutlierRule, usize>, // number of sales left out by each rule
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    // Transaction ids, listed in the sector stats only, like the sales themselves. The areas,
+    // districts and regions, and the windows of the rolling stats, only count the sales.
+    pub ids: Vec<String>,
+}
+
+impl Outliers {
+    pub fn add(&mut self, rule: OutlierRule, count: usize) {
+        self.count += count;
+        *self.rules.entry(rule).or_default() += count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(iqr_fence: Option<f64>, mad_threshold: Option<f64>) -> OutlierRules {
+        OutlierRules::new(None, None, false, iqr_fence, mad_threshold).unwrap()
+    }
+
+    #[test]
+    fn iqr_fences_leave_out_prices_at_both_ends() {
+        // P25 is 200, P75 is 220, so the fences are at 170 and 250.
+        let prices = [100, 170, 200, 210, 210, 220, 220, 251, 900];
+        let (kept, excluded) = rules(Some(1.5), None).fence(&prices);
+        assert_eq!(kept, 1..7);
+        assert_eq!(excluded, BTreeMap::from([(OutlierRule::Iqr, 3)]));
+    }
+
+    #[test]
+    fn mad_fences_are_checked_after_the_iqr_ones() {
+        let prices = [100, 200, 200, 210, 220, 220, 300];
+        // The median is 210 and the MAD is 10, so the MAD fences are 210 ± 10 / 0.6745.
+        let (kept, excluded) = rules(None, Some(1.0)).fence(&prices);
+        assert_eq!(kept, 1..6);
+        assert_eq!(excluded, BTreeMap::from([(OutlierRule::Mad, 2)]));
+
+        let (kept, excluded) = rules(Some(1.5), Some(1.0)).fence(&prices);
+        assert_eq!(kept, 1..6);
+        assert_eq!(excluded, BTreeMap::from([(OutlierRule::Iqr, 2)]));
+    }
+
+    #[test]
+    fn sale_ids_are_formatted_back_as_they_were() {
+        let guid = "{8F1B26BD-8F22-4CF1-E053-6B04A8C0A3B1}";
+        assert!(matches!(SaleId::new(guid), SaleId::Guid(_)));
+        for id in [
+            guid,
+            "{8f1b26bd-8f22-4cf1-e053-6b04a8c0a3b1}",
+            "{00000001}",
+            "",
+            "{+F1B26BD-8F22-4CF1-E053-6B04A8C0A3B1}",
+        ] {
+            assert_eq!(SaleId::new(id).to_string(), id);
+        }
+    }
+
+    #[test]
+    fn no_fences_without_spread() {
+        let prices = [100, 100, 100, 100];
+        let (kept, excluded) = rules(Some(1.5), Some(3.5)).fence(&prices);
+        assert_eq!(kept, 0..4);
+        assert!(excluded.is_empty());
+        assert_eq!(rules(Some(1.5), None).fence(&[]).0, 0..0);
+    }
+}
@@ -0,0 +1,191 @@
+use chrono::{Datelike, Months, NaiveDate};
+use std::{fmt, str::FromStr};
+
+/// Custom periods are counted from this date, unless the sales are filtered by date,
+/// in which case they are counted from the first date included.
+/// The Price Paid Dataset starts in January 1995.
+pub const DEFAULT_ORIGIN: NaiveDate = match NaiveDate::from_ymd_opt(1995, 1, 1) {
+    Some(date) => date,
+    None => panic!("invalid date"),
+};
+
+/// Average length of a year, leap years included, to turn days into years.
+pub const DAYS_PER_YEAR: f64 = 365.25;
+
+/// The length of the time periods the sales are grouped by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Year,
+    Quarter,
+    Month,
+    TaxYear,     // UK tax year, from 6 April to 5 April
+    Months(u32), // counted from the first day of the month of the origin
+    Days(u32),   // counted from the origin
+}
+
+impl Period {
+    /// The first day of the period the date falls into.
+    pub fn start(&self, date: NaiveDate, origin: NaiveDate) -> NaiveDate {
+        match *self {
+            Period::Year => start_of_months(date, 12, 0),
+            Period::Quarter => start_of_months(date, 3, 0),
+            Period::Month => start_of_months(date, 1, 0),
+            Period::TaxYear => {
+                let start = tax_year_start(date.year());
+                if date < start {
+                    tax_year_start(date.year() - 1)
+                } else {
+                    start
+                }
+            }
+            Period::Months(months) => start_of_months(date, months, month_index(origin)),
+            Period::Days(days) => {
+                let offset = (date - origin).num_days().div_euclid(days as i64) * days as i64;
+                origin
+                    .checked_add_signed(chrono::Duration::days(offset))
+                    .unwrap_or(NaiveDate::MIN)
+            }
+        }
+    }
+
+    /// The last day of the period that starts on the given date,
+    /// or the last date there is for periods that would end after it.
+    pub fn end(&self, start: NaiveDate) -> NaiveDate {
+        let next = match *self {
+            Period::Year | Period::TaxYear => start.checked_add_months(Months::new(12)),
+            Period::Quarter => start.checked_add_months(Months::new(3)),
+            Period::Month => start.checked_add_months(Months::new(1)),
+            Period::Months(months) => start.checked_add_months(Months::new(months)),
+            Period::Days(days) => start.checked_add_signed(chrono::Duration::days(days as i64)),
+        };
+        next.and_then(|next| next.pred_opt())
+            .unwrap_or(NaiveDate::MAX)
+    }
+}
+
+/// Months since the start of year 0, so that periods of months can cross years.
+fn month_index(date: NaiveDate) -> i64 {
+    date.year() as i64 * 12 + date.month0() as i64
+}
+
+/// The first day of the period of the given number of months, counted from the month index.
+fn start_of_months(date: NaiveDate, months: u32, origin: i64) -> NaiveDate {
+    let index = month_index(date);
+    let start = index - (index - origin).rem_euclid(months as i64);
+    NaiveDate::from_ymd_opt(
+        start.div_euclid(12) as i32,
+        start.rem_euclid(12) as u32 + 1,
+        1,
+    )
+    .unwrap_or(date)
+}
+
+fn tax_year_start(year: i32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, 4, 6).unwrap_or(NaiveDate::MIN)
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    /// Accepts "year", "quarter", "month" and "tax-year", or a custom length
+    /// in days, months or years, e.g. "90d", "6m" or "2y".
+    fn from_str(str: &str) -> Result<Period, String> {
+        let invalid = || {
+            format!(
+                "invalid period {:?}, expected year, quarter, month, tax-year or a length like 90d, 6m or 2y",
+                str
+            )
+        };
+        match str.to_ascii_lowercase().as_str() {
+            "year" => Ok(Period::Year),
+            "quarter" => Ok(Period::Quarter),
+            "month" => Ok(Period::Month),
+            "tax-year" => Ok(Period::TaxYear),
+            custom => {
+                let unit = custom.chars().last().ok_or_else(invalid)?;
+                let count: u32 = custom[..custom.len() - unit.len_utf8()]
+                    .parse()
+                    .map_err(|_| invalid())?;
+                match (count, unit) {
+                    (0, _) => Err(invalid()),
+                    (days, 'd') => Ok(Period::Days(days)),
+                    (months, 'm') => Ok(Period::Months(months)),
+                    (years, 'y') => years
+                        .checked_mul(12)
+                        .map(Period::Months)
+                        .ok_or_else(invalid),
+                    _ => Err(invalid()),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Period::Year => f.write_str("year"),
+            Period::Quarter => f.write_str("quarter"),
+            Period::Month => f.write_str("month"),
+            Period::TaxYear => f.write_str("tax-year"),
+            Period::Months(months) => write!(f, "{}m", months),
+            Period::Days(days) => write!(f, "{}d", days),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn tax_years_start_on_6_april() {
+        let period = Period::TaxYear;
+        assert_eq!(
+            period.start(date(2024, 4, 5), DEFAULT_ORIGIN),
+            date(2023, 4, 6)
+        );
+        assert_eq!(
+            period.start(date(2024, 4, 6), DEFAULT_ORIGIN),
+            date(2024, 4, 6)
+        );
+        assert_eq!(
+            period.start(date(2024, 1, 1), DEFAULT_ORIGIN),
+            date(2023, 4, 6)
+        );
+        assert_eq!(period.end(date(2024, 4, 6)), date(2025, 4, 5));
+    }
+
+    #[test]
+    fn months_cross_year_ends() {
+        let period = Period::Months(5);
+        let origin = date(2020, 11, 15);
+        assert_eq!(period.start(date(2021, 3, 31), origin), date(2020, 11, 1));
+        assert_eq!(period.start(date(2021, 4, 1), origin), date(2021, 4, 1));
+        assert_eq!(period.start(date(2020, 10, 31), origin), date(2020, 6, 1));
+        assert_eq!(period.end(date(2020, 11, 1)), date(2021, 3, 31));
+    }
+
+    #[test]
+    fn days_before_the_origin() {
+        let period = Period::Days(10);
+        let origin = date(2024, 1, 11);
+        assert_eq!(period.start(date(2024, 1, 10), origin), date(2024, 1, 1));
+        assert_eq!(period.start(date(2024, 1, 1), origin), date(2024, 1, 1));
+        assert_eq!(period.start(date(2023, 12, 31), origin), date(2023, 12, 22));
+        assert_eq!(period.end(date(2023, 12, 22)), date(2023, 12, 31));
+    }
+
+    #[test]
+    fn long_periods_end_on_the_last_date() {
+        for period in ["300000y", "4000000000d"] {
+            let period: Period = period.parse().unwrap();
+            let start = period.start(date(2024, 1, 1), DEFAULT_ORIGIN);
+            assert_eq!(period.end(start), NaiveDate::MAX);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use serde::{Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+/// A validated UK postcode, normalised to upper case with a single space
+/// between the outward and inward codes, e.g. "SE16 4AB".
+///
+/// The parts of the postcode form a hierarchy:
+/// area ("SE") > district ("SE16") > sector ("SE16 4") > unit ("SE16 4AB").
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Postcode {
+    value: String,
+    outward_len: usize,
+}
+
+impl Postcode {
+    /// Postcode area, the leading letters of the outward code, e.g. "SE".
+    pub fn area(&self) -> &str {
+        let len = self
+            .value
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(self.outward_len);
+        &self.value[..len]
+    }
+
+    /// Postcode district, the outward code, e.g. "SE16".
+    pub fn district(&self) -> &str {
+        &self.value[..self.outward_len]
+    }
+
+    /// Postcode sector, the outward code and the first digit of the inward code, e.g. "SE16 4".
+    pub fn sector(&self) -> &str {
+        &self.value[..self.outward_len + 2]
+    }
+
+    /// The full postcode, e.g. "SE16 4AB".
+    pub fn unit(&self) -> &str {
+        &self.value
+    }
+}
+
+impl FromStr for Postcode {
+    type Err = String;
+
+    /// Accepts postcodes in any case, with or without the space between the outward and inward codes.
+    fn from_str(str: &str) -> Result<Postcode, String> {
+        let compact: String = str
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        let invalid = || "not a valid postcode".to_string();
+
+        // The inward code is always a digit followed by two letters.
+        if compact.len() < 5 || compact.len() > 7 || !compact.is_ascii() {
+            return Err(invalid());
+        }
+        let (outward, inward) = compact.split_at(compact.len() - 3);
+        let inward_bytes = inward.as_bytes();
+        if !inward_bytes[0].is_ascii_digit()
+            || !inward_bytes[1..].iter().all(|c| c.is_ascii_alphabetic())
+        {
+            return Err(invalid());
+        }
+
+        // The outward code is one or two letters followed by a digit, and an optional
+        // digit or letter (e.g. "E1", "SE16", "EC1A", "W1U").
+        let area_len = outward
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(0);
+        let district = &outward[area_len..];
+        if !(1..=2).contains(&area_len)
+            || district.is_empty()
+            || district.len() > 2
+            || !district.as_bytes()[0].is_ascii_digit()
+            || !district.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return Err(invalid());
+        }
+
+        Ok(Postcode {
+            value: format!("{} {}", outward, inward),
+            outward_len: outward.len(),
+        })
+    }
+}
+
+impl fmt::Display for Postcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.value)
+    }
+}
+
+impl Serialize for Postcode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(postcode: &str) -> [String; 4] {
+        let postcode: Postcode = postcode.parse().unwrap();
+        [
+            postcode.area(),
+            postcode.district(),
+            postcode.sector(),
+            postcode.unit(),
+        ]
+        .map(str::to_string)
+    }
+
+    #[test]
+    fn parses_without_space_in_lower_case() {
+        assert_eq!(parts("se164ab"), ["SE", "SE16", "SE16 4", "SE16 4AB"]);
+    }
+
+    #[test]
+    fn parses_districts_ending_with_a_letter() {
+        assert_eq!(parts("EC1A 1BB"), ["EC", "EC1A", "EC1A 1", "EC1A 1BB"]);
+        assert_eq!(parts("W1U 1AA"), ["W", "W1U", "W1U 1", "W1U 1AA"]);
+    }
+
+    #[test]
+    fn rejects_invalid_postcodes() {
+        for postcode in [
+            "",
+            "   ",
+            "SE16",
+            "SE16 4A",
+            "SE16 44B",
+            "1E16 4AB",
+            "SEE16 4AB",
+            "SE164 4AB",
+            "S£16 4AB",
+        ] {
+            assert!(postcode.parse::<Postcode>().is_err(), "{:?}", postcode);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs};
+use toml::value::Datetime;
+
+use crate::error::Error;
+
+/// The configuration file read when a profile is selected, unless another one is given.
+pub const DEFAULT_CONFIG_FILE_NAME: &str = "home-uk.toml";
+
+/// A named set of options for an analysis that is run repeatedly, e.g.
+///
+/// ```toml
+/// [docklands]
+/// files = ["pp-complete.csv"]
+/// from = 2015-01-01
+/// tenure = ["leasehold"]
+/// type = ["flat"]
+/// region = ["docklands"]
+/// region-file = "regions.toml"
+/// output = "docklands.json"
+/// ```
+///
+/// The keys are the same as the long command-line options, which take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    // Input
+    #[serde(rename = "file", alias = "files")]
+    pub files: Option<Vec<String>>,
+    pub update: Option<String>,
+    pub threads: Option<usize>,
+    pub has_header: Option<bool>,
+    pub lenient: Option<bool>,
+    pub quarantine: Option<String>,
+    pub conflicts: Option<String>,
+    pub no_cache: Option<bool>,
+    // Filters
+    pub from: Option<Datetime>,
+    pub to: Option<Datetime>,
+    pub tenure: Option<Vec<String>>,
+    #[serde(rename = "type")]
+    pub property_types: Option<Vec<String>>,
+    #[serde(rename = "age")]
+    pub property_ages: Option<Vec<String>>,
+    #[serde(rename = "category")]
+    pub ppd_categories: Option<Vec<String>>,
+    pub min_price: Option<i32>,
+    pub max_price: Option<i32>,
+    pub postcodes: Option<Vec<String>>,
+    // Grouping and output
+    #[serde(rename = "region")]
+    pub regions: Option<Vec<String>>,
+    pub region_file: Option<String>,
+    pub list_min_price: Option<i32>,
+    pub list_max_price: Option<i32>,
+    pub period: Option<String>,
+    pub rolling: Option<u32>,
+    pub quantiles: Option<Vec<f64>>,
+    pub trim: Option<f64>,
+    pub histogram_bins: Option<u32>,
+    pub index: Option<String>,
+    pub bootstrap: Option<u32>,
+    pub confidence: Option<f64>,
+    pub seed: Option<u64>,
+    pub outlier_floor: Option<i32>,
+    pub outlier_ceiling: Option<i32>,
+    pub exclude_category_b: Option<bool>,
+    pub iqr_fence: Option<f64>,
+    pub mad_threshold: Option<f64>,
+    pub min_sales: Option<u64>,
+    pub fallback: Option<String>,
+    pub change_min_sales: Option<usize>,
+    pub cpi: Option<String>,
+    pub cpi_month: Option<String>,
+    pub output: Option<String>,
+}
+
+/// Reads a profile from a configuration file, where every top level table is a profile.
+pub fn load(path: &str, name: &str) -> Result<Profile, Error> {
+    let text = fs::read_to_string(path).map_err(Error::io(path))?;
+    let mut profiles: BTreeMap<String, Profile> =
+        toml::from_str(&text).map_err(|error| Error::Input {
+            path: path.to_string(),
+            message: error.to_string(),
+        })?;
+    profiles.remove(name).ok_or_else(|| Error::Input {
+        path: path.to_string(),
+        message: format!("no profile named {:?}", name),
+    })
+}
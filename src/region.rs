@@ -0,0 +1,119 @@
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::{error::Error, filter::PostcodePattern, postcode::Postcode};
+
+// Greater London is too big and includes fairly remote areas.
+const LONDON_POSTCODES: &[&str] = &[
+    "EC1A", "EC1M", "EC1N", "EC1P", "EC1R", "EC1V", "EC1Y", "EC2A", "EC2M", "EC2N", "EC2P", "EC2R",
+    "EC2V", "EC2Y", "EC3A", "EC3M", "EC3N", "EC3P", "EC3R", "EC3V", "EC4A", "EC4M", "EC4N", "EC4P",
+    "EC4R", "EC4V", "EC4Y", "WC1A", "WC1B", "WC1E", "WC1H", "WC1N", "WC1R", "WC1V", "WC1X", "WC2A",
+    "WC2B", "WC2E", "WC2H", "WC2N", "WC2R", "E1", "E2", "E3", "E4", "E5", "E6", "E7", "E8", "E9",
+    "E10", "E11", "E12", "E13", "E14", "E15", "E16", "E17", "E18", "E19", "E20", "N1", "N2", "N3",
+    "N4", "N5", "N6", "N7", "N8", "N9", "N10", "N11", "N12", "N13", "N14", "N15", "N16", "N17",
+    "N18", "N19", "N20", "N21", "N22", "NW1", "NW2", "NW3", "NW4", "NW5", "NW6", "NW7", "NW8",
+    "NW9", "NW10", "NW11", "SE1", "SE2", "SE3", "SE4", "SE5", "SE6", "SE7", "SE8", "SE9", "SE10",
+    "SE11", "SE12", "SE13", "SE14", "SE15", "SE16", "SE17", "SE18", "SE19", "SE20", "SE21", "SE22",
+    "SE23", "SE24", "SE25", "SE26", "SE27", "SE28", "SW1", "SW2", "SW3", "SW4", "SW5", "SW6",
+    "SW7", "SW8", "SW9", "SW10", "SW11", "SW12", "SW13", "SW14", "SW15", "SW16", "SW17", "SW18",
+    "SW19", "SW20", "W1", "W2", "W3", "W4", "W5", "W6", "W7", "W8", "W9", "W10", "W11", "W12",
+    "W13", "W14",
+];
+
+// Inner London still includes relatively far away areas (like E4 and N4).
+// https://en.wikipedia.org/wiki/Inner_London
+const INNER_LONDON_POSTCODES: &[&str] = &[
+    "EC1A", "EC1M", "EC1N", "EC1R", "EC1V", "EC1Y", "EC2A", "EC2M", "EC2N", "EC2R", "EC2V", "EC2Y",
+    "EC3A", "EC3M", "EC3N", "EC3R", "EC3V", "EC4A", "EC4M", "EC4N", "EC4R", "EC4V", "EC4Y", "WC1A",
+    "WC1B", "WC1E", "WC1H", "WC1N", "WC1R", "WC1V", "WC1X", "WC2A", "WC2B", "WC2E", "WC2H", "WC2N",
+    "WC2R", "E1", "E2", "E3", "E8", "E9", "E14", "E15", "E16", "N1", "N5", "N8", "N16", "NW1",
+    "NW3", "NW5", "NW6", "NW8", "NW10", "SE1", "SE3", "SE4", "SE5", "SE7", "SE8", "SE10", "SE11",
+    "SE13", "SE14", "SE15", "SE16", "SE17", "SE18", "SW1", "SW2", "SW3", "SW4", "SW5", "SW6",
+    "SW7", "SW8", "SW9", "SW10", "SW11", "W1", "W2", "W8", "W9", "W10", "W11", "W14",
+];
+
+pub const DESIRABLE_POSTCODES: &[&str] = &["E14", "E16", "SE1", "SE16"];
+
+/// Built-in regions, which can be selected by name without a region file.
+pub const PRESETS: [(&str, &[&str]); 3] = [
+    ("london", LONDON_POSTCODES),
+    ("inner-london", INNER_LONDON_POSTCODES),
+    ("desirable", DESIRABLE_POSTCODES),
+];
+
+/// A named group of postcodes, e.g. "inner-london" or a user-defined "docklands".
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub name: String,
+    pub patterns: Vec<PostcodePattern>,
+}
+
+impl Region {
+    fn new(name: &str, postcodes: &[impl AsRef<str>]) -> Region {
+        Region {
+            name: name.to_string(),
+            patterns: postcodes
+                .iter()
+                .map(|postcode| PostcodePattern::parse(postcode.as_ref()))
+                .collect(),
+        }
+    }
+
+    pub fn contains(&self, postcode: &Postcode) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.matches(postcode))
+    }
+}
+
+/// A region file maps region names to lists of postcode districts, sectors or prefixes, e.g.
+/// `docklands = ["E14", "E16", "SE16 2", "SE8*"]` in TOML or `{"docklands": ["E14", ...]}` in JSON.
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+struct RegionFile {
+    regions: BTreeMap<String, Vec<String>>,
+}
+
+fn read_region_file(path: &str) -> Result<RegionFile, Error> {
+    let text = fs::read_to_string(path).map_err(Error::io(path))?;
+    let is_json = Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+    let parse_error = |message: String| Error::Input {
+        path: path.to_string(),
+        message,
+    };
+    if is_json {
+        serde_json::from_str(&text).map_err(|error| parse_error(error.to_string()))
+    } else {
+        toml::from_str(&text).map_err(|error| parse_error(error.to_string()))
+    }
+}
+
+/// Looks up the regions with the given names, in the region file first and then in the presets.
+/// Every region of the file is used if no names are given.
+pub fn resolve(names: &[String], file: Option<&str>) -> Result<Vec<Region>, Error> {
+    let defined = match file {
+        Some(path) => read_region_file(path)?.regions,
+        None => BTreeMap::new(),
+    };
+    if names.is_empty() {
+        return Ok(defined
+            .iter()
+            .map(|(name, postcodes)| Region::new(name, postcodes))
+            .collect());
+    }
+
+    names
+        .iter()
+        .map(|name| {
+            if let Some(postcodes) = defined.get(name) {
+                return Ok(Region::new(name, postcodes));
+            }
+            match PRESETS.iter().find(|(preset, _)| preset == name) {
+                Some((_, postcodes)) => Ok(Region::new(name, postcodes)),
+                None => Err(Error::Config(format!("unknown region {:?}", name))),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,292 @@
+use chrono::NaiveDate;
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{
+    error::Error,
+    outlier::OutlierRule,
+    period::Period,
+    stats::{PriceBucket, Statistics},
+    Accumulator, Buckets, PostcodeNode, RegionBuckets,
+};
+
+/// Sorted prices of the sales in the trailing periods. Moving the window forward merges in
+/// the sorted prices of the new period and takes out the ones of the oldest period, both in
+/// linear time, rather than sorting the prices of every window from scratch.
+#[derive(Debug, Default)]
+struct Window {
+    prices: Vec<i32>,
+    real_prices: Vec<(i32, i32)>, // with their prices, in the same order, to fence the same sales
+    excluded: BTreeMap<OutlierRule, usize>, // sales left out by the rules checked for every sale
+}
+
+impl Window {
+    fn add(&mut self, accumulator: &Accumulator) {
+        merge_sorted(&mut self.prices, &accumulator.prices);
+        merge_sorted(&mut self.real_prices, &real_prices(accumulator));
+        for (rule, count) in &accumulator.excluded {
+            *self.excluded.entry(*rule).or_default() += count;
+        }
+    }
+
+    /// Takes out the prices of the accumulator, which are expected to be in the window.
+    fn remove(&mut self, accumulator: &Accumulator) {
+        remove_sorted(&mut self.prices, &accumulator.prices);
+        remove_sorted(&mut self.real_prices, &real_prices(accumulator));
+        for (rule, count) in &accumulator.excluded {
+            if let Some(excluded) = self.excluded.get_mut(rule) {
+                *excluded -= count;
+            }
+        }
+    }
+}
+
+/// The real prices of the sorted accumulator with their prices, which sorts them the same way.
+fn real_prices(accumulator: &Accumulator) -> Vec<(i32, i32)> {
+    accumulator
+        .prices
+        .iter()
+        .copied()
+        .zip(accumulator.real_prices.iter().copied())
+        .collect()
+}
+
+fn merge_sorted<T: Ord + Copy>(values: &mut Vec<T>, other: &[T]) {
+    let mut merged = Vec::with_capacity(values.len() + other.len());
+    let (mut i, mut j) = (0, 0);
+    while i < values.len() && j < other.len() {
+        if values[i] <= other[j] {
+            merged.push(values[i]);
+            i += 1;
+        } else {
+            merged.push(other[j]);
+            j += 1;
+        }
+    }
+    merged.extend_from_slice(&values[i..]);
+    merged.extend_from_slice(&other[j..]);
+    *values = merged;
+}
+
+/// Takes out one occurrence of each of the other values.
+fn remove_sorted<T: Ord>(values: &mut Vec<T>, other: &[T]) {
+    let mut removed = other.iter().peekable();
+    values.retain(|value| {
+        while removed.next_if(|removed| *removed < value).is_some() {}
+        removed.next_if_eq(&value).is_none()
+    });
+}
+
+fn sort_buckets(buckets: &mut Buckets<Accumulator>) {
+    for accumulator in buckets
+        .values_mut()
+        .flat_map(|age_buckets| age_buckets.values_mut())
+    {
+        accumulator.sort();
+    }
+}
+
+fn sort_node(node: &mut PostcodeNode<Accumulator>) {
+    sort_buckets(&mut node.buckets);
+    node.children.values_mut().for_each(sort_node);
+}
+
+fn update_buckets(
+    windows: &mut Buckets<Window>,
+    buckets: &Buckets<Accumulator>,
+    update: fn(&mut Window, &Accumulator),
+) {
+    for (property_type, age_buckets) in buckets {
+        let age_windows = windows.entry(*property_type).or_default();
+        for (property_age, accumulator) in age_buckets {
+            update(age_windows.entry(*property_age).or_default(), accumulator);
+        }
+    }
+}
+
+fn update_node(
+    window: &mut PostcodeNode<Window>,
+    node: &PostcodeNode<Accumulator>,
+    update: fn(&mut Window, &Accumulator),
+) {
+    update_buckets(&mut window.buckets, &node.buckets, update);
+    for (code, child) in &node.children {
+        update_node(
+            window.children.entry(code.clone()).or_default(),
+            child,
+            update,
+        );
+    }
+}
+
+fn update_regions(
+    windows: &mut RegionBuckets<Window>,
+    regions: &RegionBuckets<Accumulator>,
+    update: fn(&mut Window, &Accumulator),
+) {
+    for (name, buckets) in regions {
+        update_buckets(windows.entry(name.clone()).or_default(), buckets, update);
+    }
+}
+
+/// The stats of the windows that have any sales in them.
+fn snapshot_buckets(windows: &Buckets<Window>, statistics: &Statistics) -> Buckets<PriceBucket> {
+    windows
+        .iter()
+        .map(|(property_type, age_windows)| {
+            let age_buckets: BTreeMap<_, _> = age_windows
+                .iter()
+                .filter(|(_, window)| {
+                    !window.prices.is_empty() || window.excluded.values().any(|count| *count > 0)
+                })
+                .map(|(property_age, window)| {
+                    let real_prices: Vec<i32> =
+                        window.real_prices.iter().map(|(_, real)| *real).collect();
+                    let mut bucket =
+                        statistics.price_bucket(&window.prices, &real_prices, Vec::new());
+                    for (rule, count) in &window.excluded {
+                        bucket.add_outliers(*rule, *count);
+                    }
+                    (*property_age, bucket)
+                })
+                .collect();
+            (*property_type, age_buckets)
+        })
+        .filter(|(_, age_buckets)| !age_buckets.is_empty())
+        .collect()
+}
+
+fn snapshot_node(
+    window: &PostcodeNode<Window>,
+    statistics: &Statistics,
+) -> PostcodeNode<PriceBucket> {
+    PostcodeNode {
+        buckets: snapshot_buckets(&window.buckets, statistics),
+        children: window
+            .children
+            .iter()
+            .map(|(code, child)| (code.clone(), snapshot_node(child, statistics)))
+            .filter(|(_, child)| !child.buckets.is_empty())
+            .collect(),
+    }
+}
+
+fn snapshot_regions(
+    windows: &RegionBuckets<Window>,
+    statistics: &Statistics,
+) -> RegionBuckets<PriceBucket> {
+    windows
+        .iter()
+        .map(|(name, windows)| (name.clone(), snapshot_buckets(windows, statistics)))
+        .filter(|(_, buckets)| !buckets.is_empty())
+        .collect()
+}
+
+/// Calls the function with the stats of every window of the given number of consecutive periods,
+/// stepping one period at a time up to the window that ends with the last period with any sales.
+/// Windows that would start before the first period with any sales are skipped, as they would
+/// only partially cover the time they are meant to.
+pub fn for_each_window(
+    mut periods: BTreeMap<NaiveDate, PostcodeNode<Accumulator>>,
+    mut regions: BTreeMap<NaiveDate, RegionBuckets<Accumulator>>,
+    period: Period,
+    length: usize,
+    statistics: &Statistics,
+    mut f: impl FnMut(
+        NaiveDate,
+        NaiveDate,
+        PostcodeNode<PriceBucket>,
+        RegionBuckets<PriceBucket>,
+    ) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let (Some(&first), Some(&last)) = (periods.keys().next(), periods.keys().next_back()) else {
+        return Ok(());
+    };
+    periods.values_mut().for_each(sort_node);
+    regions
+        .values_mut()
+        .flat_map(|regions| regions.values_mut())
+        .for_each(sort_buckets);
+
+    let mut window = PostcodeNode::<Window>::default();
+    let mut region_windows = RegionBuckets::<Window>::new();
+    let mut starts = VecDeque::with_capacity(length + 1);
+    let mut start = first;
+    while start <= last {
+        if let Some(node) = periods.get(&start) {
+            update_node(&mut window, node, Window::add);
+        }
+        if let Some(period_regions) = regions.get(&start) {
+            update_regions(&mut region_windows, period_regions, Window::add);
+        }
+        starts.push_back(start);
+
+        if starts.len() > length {
+            let oldest = starts.pop_front().unwrap_or(start);
+            if let Some(node) = periods.remove(&oldest) {
+                update_node(&mut window, &node, Window::remove);
+            }
+            if let Some(period_regions) = regions.remove(&oldest) {
+                update_regions(&mut region_windows, &period_regions, Window::remove);
+            }
+        }
+
+        let end = period.end(start);
+        if starts.len() == length {
+            f(
+                starts[0],
+                end,
+                snapshot_node(&window, statistics),
+                snapshot_regions(&region_windows, statistics),
+            )?;
+        }
+        start = end.succ_opt().unwrap_or(NaiveDate::MAX);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accumulator(prices: &[i32]) -> Accumulator {
+        let mut accumulator = Accumulator {
+            prices: prices.to_vec(),
+            real_prices: prices.iter().map(|price| price * 2).collect(),
+            ..Accumulator::default()
+        };
+        accumulator.sort();
+        accumulator
+    }
+
+    #[test]
+    fn removing_a_period_restores_the_window() {
+        let first = accumulator(&[300, 100, 200, 200]);
+        let second = accumulator(&[200, 50, 300, 300, 400]);
+        let mut window = Window::default();
+        window.add(&first);
+        let (prices, pairs) = (window.prices.clone(), window.real_prices.clone());
+        assert_eq!(prices, [100, 200, 200, 300]);
+
+        window.add(&second);
+        assert_eq!(window.prices, [50, 100, 200, 200, 200, 300, 300, 300, 400]);
+        assert!(window.real_prices.is_sorted());
+        window.remove(&second);
+        assert_eq!((&window.prices, &window.real_prices), (&prices, &pairs));
+
+        // Moving the window forward leaves the prices of the later period only.
+        window.add(&second);
+        window.remove(&first);
+        assert_eq!(window.prices, second.prices);
+        assert_eq!(window.real_prices, real_prices(&second));
+    }
+
+    #[test]
+    fn removing_takes_out_one_duplicate_each() {
+        let mut values = vec![1, 2, 2, 2, 3, 5, 5];
+        remove_sorted(&mut values, &[2, 2, 4, 5]);
+        assert_eq!(values, [1, 2, 3, 5]);
+        merge_sorted(&mut values, &[0, 2, 2, 5, 6]);
+        assert_eq!(values, [0, 1, 2, 2, 2, 3, 5, 5, 6]);
+    }
+}
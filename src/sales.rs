@@ -0,0 +1,54 @@
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::{
+    address::{Address, PropertyKey},
+    Entry, PropertyType,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Sale {
+    pub date: NaiveDate,
+    pub price: i32,
+    pub property_type: PropertyType,
+}
+
+/// Every sale of every property, for the analyses that follow properties over time.
+#[derive(Debug, Default)]
+pub struct SalesHistory {
+    pub properties: HashMap<PropertyKey, Vec<Sale>>,
+}
+
+impl SalesHistory {
+    pub fn add(&mut self, entry: &Entry) {
+        if let Some(key) = Address::from_entry(entry).key() {
+            self.properties.entry(key).or_default().push(Sale {
+                date: entry.date,
+                price: entry.price,
+                property_type: entry.property_type,
+            });
+        }
+    }
+
+    pub fn merge(&mut self, other: SalesHistory) {
+        for (key, sales) in other.properties {
+            self.properties.entry(key).or_default().extend(sales);
+        }
+    }
+
+    /// Every property with its sales in date order, sorted by the property,
+    /// so that the results don't depend on the order of the hash map.
+    pub fn into_sorted(self) -> Vec<(PropertyKey, Vec<Sale>)> {
+        let mut properties: Vec<_> = self
+            .properties
+            .into_iter()
+            .map(|(key, mut sales)| {
+                sales.sort_unstable_by_key(|sale| (sale.date, sale.price));
+                (key, sales)
+            })
+            .collect();
+        properties.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        properties
+    }
+}
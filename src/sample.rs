@@ -0,0 +1,104 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{stats::PriceBucket, Buckets, PostcodeNode, PropertyAge, PropertyType, RegionBuckets};
+
+/// What happens to the buckets with fewer sales than the minimum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Fallback {
+    /// Keep the stats of the bucket, marked as low sample
+    Mark,
+    /// Report the stats of the same property type and age in the nearest enclosing sector,
+    /// district or area with enough sales instead
+    Parent,
+}
+
+/// Set on the buckets with fewer sales than the minimum.
+#[derive(Debug, Clone, Serialize)]
+pub struct LowSample {
+    sales: usize, // of the bucket itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback: Option<String>, // postcode whose stats are reported instead
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MinSales {
+    pub count: usize,
+    pub fallback: Fallback,
+}
+
+/// The bucket of each property type and age that buckets lower down fall back to,
+/// with the postcode it is of.
+type Parents<'a> = BTreeMap<(PropertyType, PropertyAge), (&'a str, &'a PriceBucket)>;
+
+impl MinSales {
+    /// Marks the buckets with too few sales in the period, replacing their stats
+    /// with the ones of their parents where these are to fall back to.
+    /// The changes have to be added to the buckets before, as they keep their own.
+    pub fn apply(
+        &self,
+        node: &mut PostcodeNode<PriceBucket>,
+        regions: &mut RegionBuckets<PriceBucket>,
+    ) {
+        self.apply_children(&mut node.children, &Parents::new());
+        for buckets in regions.values_mut() {
+            self.apply_buckets(buckets, &Parents::new());
+        }
+    }
+
+    fn apply_children(
+        &self,
+        children: &mut BTreeMap<String, PostcodeNode<PriceBucket>>,
+        parents: &Parents,
+    ) {
+        for (code, child) in children {
+            self.apply_buckets(&mut child.buckets, parents);
+            // Buckets that fell back themselves pass on the bucket they fell back to.
+            let mut child_parents = parents.clone();
+            for (property_type, age_buckets) in &child.buckets {
+                for (property_age, bucket) in age_buckets {
+                    if bucket.low_sample.is_none() {
+                        child_parents.insert((*property_type, *property_age), (code, bucket));
+                    }
+                }
+            }
+            self.apply_children(&mut child.children, &child_parents);
+        }
+    }
+
+    fn apply_buckets(&self, buckets: &mut Buckets<PriceBucket>, parents: &Parents) {
+        for (property_type, age_buckets) in buckets {
+            for (property_age, bucket) in age_buckets {
+                if bucket.count >= self.count {
+                    continue;
+                }
+                let sales = bucket.count;
+                let parent = match self.fallback {
+                    Fallback::Parent => parents.get(&(*property_type, *property_age)),
+                    Fallback::Mark => None,
+                };
+                if let Some((_, parent)) = parent {
+                    // The sales of the bucket itself are still the ones listed and left out,
+                    // and its changes are still of its own sales, flagged by their own count.
+                    let properties = std::mem::take(&mut bucket.properties);
+                    let own = (bucket.change.take(), bucket.outliers.take());
+                    let real_own = bucket
+                        .real
+                        .as_mut()
+                        .map(|real| (real.change.take(), real.outliers.take()));
+                    *bucket = (*parent).clone();
+                    bucket.properties = properties;
+                    (bucket.change, bucket.outliers) = own;
+                    if let Some(real) = &mut bucket.real {
+                        (real.change, real.outliers) = real_own.unwrap_or_default();
+                    }
+                }
+                bucket.low_sample = Some(LowSample {
+                    sales,
+                    fallback: parent.map(|(code, _)| code.to_string()),
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::ops::RangeInclusive;
+
+use crate::{
+    bootstrap::Bootstrap,
+    change::Changes,
+    error::Error,
+    outlier::{OutlierRule, OutlierRules, Outliers},
+    sample::LowSample,
+    Property,
+};
+
+pub const DEFAULT_QUANTILES: [f64; 4] = [10.0, 25.0, 75.0, 90.0];
+pub const DEFAULT_TRIM: f64 = 10.0;
+pub const DEFAULT_HISTOGRAM_BINS: u32 = 10;
+
+/// Which statistics are calculated for every bucket, on top of the count, mean, median and range.
+#[derive(Debug, Clone)]
+pub struct Statistics {
+    pub quantiles: Vec<f64>, // percentiles, e.g. 10 for P10
+    pub trim: f64,           // percentage of the prices left out at each end for the trimmed mean
+    pub histogram_bins: u32, // bins per tenfold increase in price, none if 0
+    pub outliers: OutlierRules,
+    pub bootstrap: Option<Bootstrap>, // for the confidence intervals of the median and quantiles
+}
+
+impl Statistics {
+    pub fn new(
+        quantiles: Vec<f64>,
+        trim: f64,
+        histogram_bins: u32,
+        outliers: OutlierRules,
+        bootstrap: Option<Bootstrap>,
+    ) -> Result<Statistics, Error> {
+        if let Some(quantile) = quantiles.iter().find(|q| !(0.0..=100.0).contains(*q)) {
+            return Err(Error::Config(format!(
+                "quantile {} is not between 0 and 100",
+                quantile
+            )));
+        }
+        if !(0.0..50.0).contains(&trim) {
+            return Err(Error::Config(format!(
+                "trim {} is not at least 0 and less than 50",
+                trim
+            )));
+        }
+        Ok(Statistics {
+            quantiles,
+            trim,
+            histogram_bins,
+            outliers,
+            bootstrap,
+        })
+    }
+
+    /// Calculates the stats of a bucket from its prices, which have to be sorted, as well as
+    /// the stats of the inflation-adjusted prices, if there are any, in the order of the prices.
+    /// The sales outside the outlier fences of the prices are left out of both.
+    pub fn price_bucket(
+        &self,
+        prices: &[i32],
+        real_prices: &[i32],
+        properties: Vec<Property>,
+    ) -> PriceBucket {
+        let (kept, excluded) = self.outliers.fence(prices);
+        let mut bucket = self.kept_price_bucket(&prices[kept.clone()], properties);
+        if let Some(real_prices) = real_prices.get(kept).filter(|prices| !prices.is_empty()) {
+            let mut real_prices = real_prices.to_vec();
+            real_prices.sort_unstable();
+            bucket.real = Some(Box::new(self.kept_price_bucket(&real_prices, Vec::new())));
+        }
+        for (rule, count) in excluded {
+            bucket.add_outliers(rule, count);
+        }
+        bucket
+    }
+
+    /// Calculates the stats of the sorted prices in a single pass over them.
+    fn kept_price_bucket(&self, prices: &[i32], properties: Vec<Property>) -> PriceBucket {
+        let count = prices.len();
+        let trimmed = trimmed_range(count, self.trim);
+        let mut sum = 0f64;
+        let mut log_sum = 0f64;
+        let mut trimmed_sum = 0f64;
+        // Welford's algorithm, as the sum of squares of prices loses too much precision.
+        let mut mean = 0f64;
+        let mut squared_deviations = 0f64;
+        let mut first_bin = None;
+        let mut bin_counts: Vec<usize> = Vec::new();
+        for (index, &price) in prices.iter().enumerate() {
+            let value = price as f64;
+            sum += value;
+            log_sum += value.max(1.0).ln();
+            if trimmed.contains(&index) {
+                trimmed_sum += value;
+            }
+            let delta = value - mean;
+            mean += delta / (index + 1) as f64;
+            squared_deviations += delta * (value - mean);
+            if self.histogram_bins > 0 {
+                // The prices are sorted, so the first one is in the lowest bin.
+                let bin = bin_index(price, self.histogram_bins);
+                let offset = (bin - *first_bin.get_or_insert(bin)) as usize;
+                if bin_counts.len() <= offset {
+                    bin_counts.resize(offset + 1, 0);
+                }
+                bin_counts[offset] += 1;
+            }
+        }
+        let histogram = bin_counts
+            .into_iter()
+            .enumerate()
+            .map(|(offset, count)| {
+                let bin = first_bin.unwrap_or(0) + offset as i32;
+                HistogramBin {
+                    min: bin_min(bin, self.histogram_bins),
+                    max: bin_min(bin + 1, self.histogram_bins),
+                    count,
+                }
+            })
+            .collect();
+
+        let quantile = |percentile: f64| find_quantile(prices, percentile);
+        let mut bucket = PriceBucket {
+            count,
+            mean: match count {
+                0 => 0.0,
+                _ => (sum / count as f64) as f32,
+            },
+            median: find_median(prices) as f32,
+            range: *prices.first().unwrap_or(&0)..=*prices.last().unwrap_or(&0),
+            quantiles: Quantiles(
+                self.quantiles
+                    .iter()
+                    .map(|&percentile| (percentile, quantile(percentile)))
+                    .collect(),
+            ),
+            iqr: quantile(75.0) - quantile(25.0),
+            std_dev: match count {
+                0 | 1 => 0.0,
+                _ => (squared_deviations / (count - 1) as f64).sqrt() as f32,
+            },
+            trimmed_mean: (trimmed_sum / (trimmed.end() + 1 - trimmed.start()) as f64) as f32,
+            geometric_mean: match count {
+                0 => 0.0,
+                _ => (log_sum / count as f64).exp() as f32,
+            },
+            histogram,
+            real: None,
+            change: None,
+            outliers: None,
+            low_sample: None,
+            intervals: None,
+            bootstrap_medians: Vec::new(),
+            properties,
+        };
+        if let Some(bootstrap) = self.bootstrap.as_ref().filter(|_| count > 0) {
+            let mut resamples = bootstrap.resample(prices, &self.quantiles);
+            bucket.intervals = bootstrap
+                .interval(&mut resamples.medians)
+                .map(|median| Intervals {
+                    median,
+                    quantiles: Quantiles(
+                        self.quantiles
+                            .iter()
+                            .zip(&mut resamples.quantiles)
+                            .filter_map(|(&percentile, values)| {
+                                Some((percentile, bootstrap.interval(values)?))
+                            })
+                            .collect(),
+                    ),
+                });
+            bucket.bootstrap_medians = resamples.medians;
+        }
+        bucket
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PriceBucket {
+    pub count: usize,
+    pub mean: f32,
+    pub median: f32,
+    pub range: RangeInclusive<i32>,
+    pub quantiles: Quantiles,
+    pub iqr: f32, // interquartile range, P75 - P25
+    pub std_dev: f32,
+    pub trimmed_mean: f32,
+    pub geometric_mean: f32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub histogram: Vec<HistogramBin>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub real: Option<Box<PriceBucket>>, // stats of the prices in the money of the reference month
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change: Option<Changes>, // of the median against earlier periods
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outliers: Option<Outliers>, // sales left out of the stats
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub low_sample: Option<LowSample>, // too few sales for the stats to be reliable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub intervals: Option<Intervals>, // bootstrap confidence intervals
+    #[serde(skip)]
+    pub bootstrap_medians: Vec<f32>, // for the intervals of the changes between periods
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub properties: Vec<Property>,
+}
+
+/// Prices at the selected percentiles, serialized as e.g. `{"p10": 250000, "p25": 310000}`.
+#[derive(Debug, Clone, Default)]
+pub struct Quantiles<T = f32>(Vec<(f64, T)>);
+
+impl PriceBucket {
+    /// Records sales left out of the stats before the prices got to the bucket.
+    pub fn add_outliers(&mut self, rule: OutlierRule, count: usize) {
+        if count > 0 {
+            self.outliers
+                .get_or_insert_with(Outliers::default)
+                .add(rule, count);
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for Quantiles<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (percentile, price) in &self.0 {
+            map.serialize_entry(&format!("p{}", percentile), price)?;
+        }
+        map.end()
+    }
+}
+
+/// Confidence intervals of the median and quantiles, as [lower, upper].
+#[derive(Debug, Clone, Serialize)]
+pub struct Intervals {
+    pub median: [f32; 2],
+    pub quantiles: Quantiles<[f32; 2]>,
+}
+
+/// Number of sales with prices from `min` up to, but not including, `max`.
+/// The bins are spaced evenly on a logarithmic scale and are the same for every bucket,
+/// so that the histograms can be compared.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramBin {
+    pub min: i32,
+    pub max: i32,
+    pub count: usize,
+}
+
+fn bin_min(index: i32, bins: u32) -> i32 {
+    10f64.powf(index as f64 / bins as f64).round() as i32
+}
+
+fn bin_index(price: i32, bins: u32) -> i32 {
+    let mut index = ((price.max(1) as f64).log10() * bins as f64).floor() as i32;
+    // The bin boundaries are rounded to whole pounds, which the logarithm doesn't know about.
+    while index > 0 && bin_min(index, bins) > price {
+        index -= 1;
+    }
+    while bin_min(index + 1, bins) <= price {
+        index += 1;
+    }
+    index
+}
+
+/// Indices of the prices left once the given percentage is trimmed from both ends.
+/// At least the middle price (or two) is always kept.
+fn trimmed_range(count: usize, trim: f64) -> RangeInclusive<usize> {
+    let trimmed = ((count as f64 * trim / 100.0).floor() as usize).min(count.saturating_sub(1) / 2);
+    trimmed..=count.saturating_sub(trimmed + 1)
+}
+
+/// Linearly interpolates between the closest ranks, so that P50 is the median.
+pub fn find_quantile(prices: &[i32], percentile: f64) -> f32 {
+    if prices.is_empty() {
+        return 0.0;
+    }
+    let rank = percentile / 100.0 * (prices.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    let fraction = rank - lower as f64;
+    (prices[lower] as f64 + (prices[upper] as f64 - prices[lower] as f64) * fraction) as f32
+}
+
+/// The median of sorted values, 0 without any.
+pub fn find_median<T: Copy + Into<f64>>(values: &[T]) -> f64 {
+    let len = values.len();
+    if len == 0 {
+        0.0
+    } else if len >= 2 && len.is_multiple_of(2) {
+        let middle = len / 2;
+        (values[middle - 1].into() + values[middle].into()) / 2.0
+    } else {
+        values[len / 2].into()
+    }
+}